@@ -0,0 +1,354 @@
+use anyhow::{Context, Result};
+use image::codecs::gif::GifDecoder;
+use image::{AnimationDecoder, ImageDecoder, ImageFormat, RgbaImage};
+use std::io::Cursor;
+use tracing::debug;
+use webp::{AnimDecoder, AnimEncoder, AnimFrame, BitstreamFeatures, WebPConfig};
+
+// GIF application extension that carries the loop count
+const NETSCAPE_LOOP_EXTENSION: &[u8] = b"NETSCAPE2.0";
+
+// Browsers treat very short GIF delays as 100ms, so we do the same
+const MIN_FRAME_DELAY_MS: u32 = 20;
+const DEFAULT_FRAME_DELAY_MS: u32 = 100;
+
+/// A decoded animation with fully composited frames
+pub struct Animation {
+    pub frames: Vec<AnimationFrame>,
+    /// Number of times the animation plays, 0 meaning forever
+    pub loop_count: u16,
+}
+
+/// A single composited frame and how long it stays on screen
+pub struct AnimationFrame {
+    pub image: RgbaImage,
+    pub delay_ms: u32,
+}
+
+/// Check whether GIF or WebP data holds more than one frame
+pub fn is_animated(data: &[u8], format: ImageFormat) -> bool {
+    match format {
+        ImageFormat::Gif => GifDecoder::new(Cursor::new(data))
+            .map(|decoder| decoder.into_frames().take(2).count() > 1)
+            .unwrap_or(false),
+        ImageFormat::WebP => BitstreamFeatures::new(data)
+            .map(|features| features.has_animation())
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Decode every frame of an animated GIF or WebP. Fails once the decoded
+/// frames would take more than `max_alloc` bytes.
+pub fn decode(data: &[u8], format: ImageFormat, max_alloc: u64) -> Result<Animation> {
+    match format {
+        ImageFormat::Gif => decode_gif(data, max_alloc),
+        ImageFormat::WebP => decode_webp(data, max_alloc),
+        _ => anyhow::bail!("{:?} images can't be animated", format),
+    }
+}
+
+fn decode_gif(data: &[u8], max_alloc: u64) -> Result<Animation> {
+    let decoder = GifDecoder::new(Cursor::new(data))?;
+    let (width, height) = decoder.dimensions();
+    let frame_bytes = width as u64 * height as u64 * 4;
+
+    // Frames are checked one at a time, so a GIF with endless tiny frames
+    // can't run the server out of memory
+    let mut frames = Vec::new();
+    for frame in decoder.into_frames() {
+        let frame = frame.with_context(|| "Failed to decode GIF frames")?;
+        if (frames.len() as u64 + 1) * frame_bytes > max_alloc {
+            anyhow::bail!(
+                "Animation has more {}x{} frames than fit in the {} byte decode limit",
+                width,
+                height,
+                max_alloc
+            );
+        }
+
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let delay_ms = numer.checked_div(denom).unwrap_or(0);
+        frames.push(AnimationFrame {
+            image: frame.into_buffer(),
+            delay_ms: normalize_delay(delay_ms),
+        });
+    }
+
+    Ok(Animation {
+        frames,
+        loop_count: gif_loop_count(data),
+    })
+}
+
+fn decode_webp(data: &[u8], max_alloc: u64) -> Result<Animation> {
+    // libwebp decodes every frame in one go, so the frames are counted first
+    let features = BitstreamFeatures::new(data)
+        .ok_or_else(|| anyhow::anyhow!("Failed to read WebP header"))?;
+    let frame_count = webp_frame_count(data) as u64;
+    let frame_bytes = features.width() as u64 * features.height() as u64 * 4;
+    if frame_count * frame_bytes > max_alloc {
+        anyhow::bail!(
+            "Animation has {} {}x{} frames, more than fit in the {} byte decode limit",
+            frame_count,
+            features.width(),
+            features.height(),
+            max_alloc
+        );
+    }
+
+    let decoded = AnimDecoder::new(data)
+        .decode()
+        .map_err(|e| anyhow::anyhow!("Failed to decode animated WebP: {}", e))?;
+
+    // libwebp reports the time each frame ends, so delays are the differences
+    let mut frames = Vec::with_capacity(decoded.len());
+    let mut previous_end = 0;
+    for index in 0..decoded.len() {
+        let Some(frame) = decoded.get_frame(index) else {
+            continue;
+        };
+
+        let image: image::DynamicImage = (&frame).into();
+        let end = frame.get_time_ms();
+        frames.push(AnimationFrame {
+            image: image.to_rgba8(),
+            delay_ms: normalize_delay(end.saturating_sub(previous_end).max(0) as u32),
+        });
+        previous_end = end;
+    }
+
+    Ok(Animation {
+        frames,
+        loop_count: decoded.loop_count.min(u16::MAX as u32) as u16,
+    })
+}
+
+/// Read the loop count from the NETSCAPE2.0 extension. GIFs without it play
+/// once, and its count is the number of extra plays after the first.
+fn gif_loop_count(data: &[u8]) -> u16 {
+    let Some(position) = data
+        .windows(NETSCAPE_LOOP_EXTENSION.len())
+        .position(|window| window == NETSCAPE_LOOP_EXTENSION)
+    else {
+        return 1;
+    };
+
+    // Sub-block: size (3), id (1), loop count (u16 little endian)
+    let block = &data[position + NETSCAPE_LOOP_EXTENSION.len()..];
+    match block {
+        [3, 1, low, high, ..] => match u16::from_le_bytes([*low, *high]) {
+            0 => 0,
+            repeats => repeats.saturating_add(1),
+        },
+        _ => 1,
+    }
+}
+
+/// Number of frames in an animated WebP, counted from its ANMF chunks
+fn webp_frame_count(data: &[u8]) -> usize {
+    // Chunks follow the 12 byte RIFF header, each padded to an even size
+    let mut count = 0;
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let size = u32::from_le_bytes([
+            data[offset + 4],
+            data[offset + 5],
+            data[offset + 6],
+            data[offset + 7],
+        ]) as usize;
+        if &data[offset..offset + 4] == b"ANMF" {
+            count += 1;
+        }
+        offset = offset.saturating_add(8 + size + (size & 1));
+    }
+    count
+}
+
+fn normalize_delay(delay_ms: u32) -> u32 {
+    if delay_ms < MIN_FRAME_DELAY_MS {
+        DEFAULT_FRAME_DELAY_MS
+    } else {
+        delay_ms
+    }
+}
+
+impl Animation {
+    /// Drop frames to shrink the output: keep every `step`-th frame and at
+    /// most `max_fps` frames per second. Dropped frames hand their delay to the
+    /// frame before them so the total running time is unchanged.
+    pub fn reduce_frames(&mut self, step: usize, max_fps: Option<f32>) {
+        let step = step.max(1);
+        let min_interval_ms = max_fps
+            .filter(|fps| *fps > 0.0)
+            .map(|fps| (1000.0 / fps).round() as u32)
+            .unwrap_or(0);
+
+        let original_count = self.frames.len();
+        let mut kept: Vec<AnimationFrame> = Vec::with_capacity(original_count);
+
+        for (index, frame) in std::mem::take(&mut self.frames).into_iter().enumerate() {
+            let keep = match kept.last() {
+                None => true,
+                Some(last) => index % step == 0 && last.delay_ms >= min_interval_ms,
+            };
+
+            if keep {
+                kept.push(frame);
+            } else if let Some(last) = kept.last_mut() {
+                last.delay_ms += frame.delay_ms;
+            }
+        }
+
+        if kept.len() != original_count {
+            debug!(
+                "Reduced animation from {} to {} frames",
+                original_count,
+                kept.len()
+            );
+        }
+
+        self.frames = kept;
+    }
+
+    /// Encode as an animated WebP
+    pub fn encode_webp(&self, quality: f32) -> Result<Vec<u8>> {
+        let Some(first) = self.frames.first() else {
+            anyhow::bail!("Animation has no frames");
+        };
+        let (width, height) = first.image.dimensions();
+
+        let mut config =
+            WebPConfig::new().map_err(|_| anyhow::anyhow!("Failed to create WebP config"))?;
+        config.quality = quality;
+
+        let mut encoder = AnimEncoder::new(width, height, &config);
+        encoder.set_loop_count(self.loop_count as i32);
+
+        let mut timestamp = 0;
+        for frame in &self.frames {
+            encoder.add_frame(AnimFrame::from_rgba(
+                frame.image.as_raw(),
+                width,
+                height,
+                timestamp as i32,
+            ));
+            timestamp += frame.delay_ms;
+        }
+
+        let encoded = encoder
+            .try_encode()
+            .map_err(|e| anyhow::anyhow!("Failed to encode animated WebP: {:?}", e))?;
+
+        let mut data = encoded.to_vec();
+        set_last_frame_end(&mut data, timestamp);
+        Ok(data)
+    }
+}
+
+/// The webp crate closes the animation without an end timestamp, which makes
+/// libwebp guess the last frame's delay. Rewrite the last ANMF chunk so the
+/// animation ends at `end_ms`.
+fn set_last_frame_end(data: &mut [u8], end_ms: u32) {
+    // RIFF header: "RIFF", size, "WEBP"
+    let mut offset = 12;
+    let mut elapsed = 0u32;
+    let mut last_duration_at = None;
+
+    while offset + 8 <= data.len() {
+        let id = &data[offset..offset + 4];
+        let size = u32::from_le_bytes([
+            data[offset + 4],
+            data[offset + 5],
+            data[offset + 6],
+            data[offset + 7],
+        ]) as usize;
+
+        // ANMF payload: x, y, width - 1, height - 1, duration (24 bits each)
+        if id == b"ANMF" && offset + 8 + 15 <= data.len() {
+            if let Some(previous) = last_duration_at {
+                elapsed += read_u24(data, previous);
+            }
+            last_duration_at = Some(offset + 8 + 12);
+        }
+
+        // Chunks are padded to an even size
+        offset += 8 + size + (size & 1);
+    }
+
+    if let Some(at) = last_duration_at {
+        let duration = end_ms.saturating_sub(elapsed).clamp(1, 0xFF_FFFF);
+        data[at..at + 3].copy_from_slice(&duration.to_le_bytes()[..3]);
+    }
+}
+
+fn read_u24(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], 0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An animation of solid frames, each a different color
+    fn animation(delays: &[u32], loop_count: u16) -> Animation {
+        let frames = delays
+            .iter()
+            .enumerate()
+            .map(|(index, &delay_ms)| AnimationFrame {
+                image: RgbaImage::from_pixel(8, 8, image::Rgba([(index * 25) as u8, 0, 0, 255])),
+                delay_ms,
+            })
+            .collect();
+        Animation { frames, loop_count }
+    }
+
+    fn round_trip(animation: &Animation) -> Animation {
+        let encoded = animation.encode_webp(90.0).unwrap();
+        assert_eq!(webp_frame_count(&encoded), animation.frames.len());
+        decode(&encoded, ImageFormat::WebP, u64::MAX).unwrap()
+    }
+
+    fn delays(animation: &Animation) -> Vec<u32> {
+        animation
+            .frames
+            .iter()
+            .map(|frame| frame.delay_ms)
+            .collect()
+    }
+
+    #[test]
+    fn webp_round_trip_keeps_frames_delays_and_loops() {
+        let original = animation(&[100, 250, 40, 300], 3);
+        let decoded = round_trip(&original);
+
+        assert_eq!(decoded.frames.len(), 4);
+        assert_eq!(delays(&decoded), [100, 250, 40, 300]);
+        assert_eq!(delays(&decoded).iter().sum::<u32>(), 690);
+        assert_eq!(decoded.loop_count, 3);
+    }
+
+    #[test]
+    fn reduced_frames_keep_the_total_duration() {
+        let mut reduced = animation(&[100, 200, 100, 200, 100], 0);
+        reduced.reduce_frames(2, None);
+        assert_eq!(delays(&reduced), [300, 300, 100]);
+
+        let decoded = round_trip(&reduced);
+        assert_eq!(decoded.frames.len(), 3);
+        assert_eq!(delays(&decoded), [300, 300, 100]);
+        assert_eq!(decoded.loop_count, 0);
+    }
+
+    #[test]
+    fn frame_rate_cap_merges_short_frames() {
+        let mut reduced = animation(&[40; 10], 1);
+        reduced.reduce_frames(1, Some(10.0));
+        assert_eq!(delays(&reduced), [120, 120, 120, 40]);
+
+        let decoded = round_trip(&reduced);
+        assert_eq!(delays(&decoded), [120, 120, 120, 40]);
+        assert_eq!(delays(&decoded).iter().sum::<u32>(), 400);
+        assert_eq!(decoded.loop_count, 1);
+    }
+}
@@ -0,0 +1,183 @@
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+use tracing::debug;
+
+use crate::optimizer::{OptimizationOptions, OptimizationReport};
+
+/// An optimized output kept for identical requests
+#[derive(Debug, Clone)]
+pub struct CachedResult {
+    /// Bytes of the written file
+    pub data: Vec<u8>,
+    /// Extension the file was written with, which differs from the requested
+    /// format when the original was kept
+    pub extension: String,
+    pub report: OptimizationReport,
+}
+
+/// In-memory cache of optimized outputs keyed by the input bytes and the
+/// options they were optimized with. The least recently used entries are
+/// evicted once the stored outputs exceed `max_bytes`.
+pub struct ResultCache {
+    entries: Mutex<CacheEntries>,
+    max_bytes: usize,
+}
+
+struct CacheEntries {
+    lru: LruCache<String, CachedResult>,
+    bytes: usize,
+}
+
+impl ResultCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            entries: Mutex::new(CacheEntries {
+                lru: LruCache::unbounded(),
+                bytes: 0,
+            }),
+            max_bytes,
+        }
+    }
+
+    /// Look up a result, marking it as recently used
+    pub fn get(&self, key: &str) -> Option<CachedResult> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.lru.get(key).cloned()
+    }
+
+    /// Store a result, evicting older ones to stay within the size limit.
+    /// Outputs bigger than the whole cache aren't stored.
+    pub fn insert(&self, key: String, result: CachedResult) {
+        if result.data.len() > self.max_bytes {
+            debug!(
+                "Not caching a {} byte output, the cache holds {} bytes",
+                result.data.len(),
+                self.max_bytes
+            );
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.bytes += result.data.len();
+        if let Some(previous) = entries.lru.put(key, result) {
+            entries.bytes -= previous.data.len();
+        }
+
+        while entries.bytes > self.max_bytes {
+            let Some((key, evicted)) = entries.lru.pop_lru() else {
+                break;
+            };
+            entries.bytes -= evicted.data.len();
+            debug!(
+                "Evicted cached result {} ({} bytes)",
+                key,
+                evicted.data.len()
+            );
+        }
+    }
+}
+
+/// Cache key for an input optimized with the given options: a SHA-256 over
+/// the input bytes, the options and the watermark's pixels
+pub fn key(data: &[u8], options: &OptimizationOptions) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+
+    // The watermark is hashed by content, since a name can be uploaded again
+    // with a different image
+    let mut settings = options.clone();
+    let watermark = settings.watermark.image.take();
+    hasher.update(format!("{:?}", settings).as_bytes());
+    if let Some(watermark) = watermark {
+        hasher.update(watermark.width().to_le_bytes());
+        hasher.update(watermark.height().to_le_bytes());
+        hasher.update(watermark.as_raw());
+    }
+
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbaImage;
+    use std::sync::Arc;
+
+    fn result(size: usize) -> CachedResult {
+        CachedResult {
+            data: vec![0; size],
+            extension: "webp".to_string(),
+            report: OptimizationReport::default(),
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used_past_max_bytes() {
+        let cache = ResultCache::new(100);
+        cache.insert("a".to_string(), result(40));
+        cache.insert("b".to_string(), result(40));
+
+        // Reading "a" leaves "b" as the least recently used
+        assert!(cache.get("a").is_some());
+        cache.insert("c".to_string(), result(40));
+
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+        assert_eq!(cache.entries.lock().unwrap().bytes, 80);
+    }
+
+    #[test]
+    fn replacing_an_entry_counts_only_the_new_bytes() {
+        let cache = ResultCache::new(100);
+        cache.insert("a".to_string(), result(60));
+        cache.insert("a".to_string(), result(30));
+        cache.insert("b".to_string(), result(70));
+
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_some());
+        assert_eq!(cache.entries.lock().unwrap().bytes, 100);
+    }
+
+    #[test]
+    fn never_stores_outputs_larger_than_the_cache() {
+        let cache = ResultCache::new(100);
+        cache.insert("a".to_string(), result(50));
+        cache.insert("huge".to_string(), result(101));
+
+        assert!(cache.get("huge").is_none());
+        assert!(cache.get("a").is_some());
+        assert_eq!(cache.entries.lock().unwrap().bytes, 50);
+    }
+
+    #[test]
+    fn key_covers_input_options_and_watermark() {
+        let options = OptimizationOptions::default();
+        let base = key(b"image", &options);
+        assert_eq!(base, key(b"image", &options));
+        assert_ne!(base, key(b"other", &options));
+
+        let other_quality = OptimizationOptions {
+            quality: options.quality + 1.0,
+            ..OptimizationOptions::default()
+        };
+        assert_ne!(base, key(b"image", &other_quality));
+
+        // Same watermark name, different pixels
+        let mut marked = OptimizationOptions::default();
+        marked.watermark.name = Some("logo".to_string());
+        marked.watermark.image = Some(Arc::new(RgbaImage::new(4, 4)));
+        let mut remarked = marked.clone();
+        remarked.watermark.image = Some(Arc::new(RgbaImage::from_pixel(
+            4,
+            4,
+            image::Rgba([255, 0, 0, 255]),
+        )));
+        assert_ne!(key(b"image", &marked), key(b"image", &remarked));
+    }
+}
@@ -0,0 +1,335 @@
+use ab_glyph::{point, Font, FontRef, PxScale, ScaleFont};
+use image::{DynamicImage, Rgba, RgbaImage};
+use tracing::{debug, warn};
+
+use crate::watermark::{self, WatermarkPosition};
+
+// DejaVu Sans ships inside the binary so captions look the same on every host
+pub static FONT_DATA: &[u8] = include_bytes!("../assets/fonts/DejaVuSans.ttf");
+
+// Without an explicit size, captions scale with the shorter side of the image
+const CAPTION_SIZE_RATIO: f32 = 0.04;
+const MIN_CAPTION_SIZE: f32 = 12.0;
+const CAPTION_MARGIN: u32 = 16;
+
+// Shadow offset and outline width, as a fraction of the font size, and the
+// most either may grow to
+const CAPTION_EFFECT_RATIO: f32 = 0.06;
+const MAX_CAPTION_EFFECT: u32 = 24;
+
+// Lines past this are dropped, so a long paste can't make the label huge
+const MAX_CAPTION_LINES: usize = 10;
+
+/// Text rendered onto images before encoding
+#[derive(Debug, Clone)]
+pub struct CaptionSettings {
+    /// Text to render, one line per `\n`
+    pub text: Option<String>,
+    /// Font size in pixels, scaled with the image when unset
+    pub size: Option<f32>,
+    /// RGBA fill of the glyphs
+    pub color: [u8; 4],
+    /// RGBA drop shadow, offset down and to the right
+    pub shadow: Option<[u8; 4]>,
+    /// RGBA outline around the glyphs
+    pub outline: Option<[u8; 4]>,
+    pub position: WatermarkPosition,
+    /// Distance from the edges, and between tiles, in pixels
+    pub margin: u32,
+}
+
+impl Default for CaptionSettings {
+    fn default() -> Self {
+        Self {
+            text: None,
+            size: None,
+            color: [255, 255, 255, 255],
+            shadow: None,
+            outline: None,
+            // Out of the way of a logo in the default corner
+            position: WatermarkPosition::BottomLeft,
+            margin: CAPTION_MARGIN,
+        }
+    }
+}
+
+impl CaptionSettings {
+    /// Whether there is any text to render
+    pub fn is_active(&self) -> bool {
+        self.text
+            .as_deref()
+            .is_some_and(|text| !text.trim().is_empty())
+    }
+}
+
+/// Glyph coverage (0-1) of the rendered text
+#[derive(Clone)]
+struct Coverage {
+    width: u32,
+    height: u32,
+    values: Vec<f32>,
+}
+
+impl Coverage {
+    fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            values: vec![0.0; width as usize * height as usize],
+        }
+    }
+
+    fn get(&self, x: i64, y: i64) -> f32 {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            return 0.0;
+        }
+        self.values[self.index(x as u32, y as u32)]
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        y as usize * self.width as usize + x as usize
+    }
+
+    /// The same coverage moved `offset` pixels down and to the right
+    fn shifted(&self, offset: u32) -> Self {
+        let mut shifted = Coverage::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let index = shifted.index(x, y);
+                shifted.values[index] =
+                    self.get(x as i64 - offset as i64, y as i64 - offset as i64);
+            }
+        }
+        shifted
+    }
+
+    /// The coverage grown by `radius` pixels in every direction: a square
+    /// max filter, run along the rows and then along the columns
+    fn dilated(&self, radius: u32) -> Self {
+        self.max_filter(radius, (1, 0)).max_filter(radius, (0, 1))
+    }
+
+    /// Maximum over the `2 * radius + 1` pixels centered on each pixel along
+    /// `step`
+    fn max_filter(&self, radius: u32, (step_x, step_y): (i64, i64)) -> Self {
+        let radius = radius as i64;
+        let mut filtered = Coverage::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let max = (-radius..=radius)
+                    .map(|offset| self.get(x as i64 + offset * step_x, y as i64 + offset * step_y))
+                    .fold(0.0, f32::max);
+                let index = filtered.index(x, y);
+                filtered.values[index] = max;
+            }
+        }
+        filtered
+    }
+}
+
+/// Render the caption onto an image. Images without caption text are
+/// returned as they are.
+pub fn apply(img: DynamicImage, settings: &CaptionSettings) -> DynamicImage {
+    let Some(text) = settings.text.as_deref().filter(|_| settings.is_active()) else {
+        return img;
+    };
+
+    let font = match FontRef::try_from_slice(FONT_DATA) {
+        Ok(font) => font,
+        Err(e) => {
+            warn!("Failed to load the caption font: {}", e);
+            return img;
+        }
+    };
+
+    if text.lines().count() > MAX_CAPTION_LINES {
+        warn!(
+            "Caption has more than {} lines, drawing only the first ones",
+            MAX_CAPTION_LINES
+        );
+    }
+    let text = text
+        .lines()
+        .take(MAX_CAPTION_LINES)
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut canvas = img.into_rgba8();
+    let (width, height) = canvas.dimensions();
+
+    let size = settings
+        .size
+        .unwrap_or_else(|| (width.min(height) as f32 * CAPTION_SIZE_RATIO).max(MIN_CAPTION_SIZE))
+        .min(height as f32);
+
+    // Long or tall captions shrink to fit between the margins
+    let available_width = width
+        .saturating_sub(settings.margin.saturating_mul(2))
+        .max(1) as f32;
+    let available_height = height
+        .saturating_sub(settings.margin.saturating_mul(2))
+        .max(1) as f32;
+    let natural_width = line_widths(&font, size, &text)
+        .into_iter()
+        .fold(0.0, f32::max);
+    let natural_height = text_height(&font, size, text.lines().count());
+    let fit = (available_width / natural_width)
+        .min(available_height / natural_height)
+        .min(1.0);
+    let size = size * fit;
+
+    let effect = if settings.shadow.is_some() || settings.outline.is_some() {
+        ((size * CAPTION_EFFECT_RATIO).round() as u32).clamp(1, MAX_CAPTION_EFFECT)
+    } else {
+        0
+    };
+
+    let label = render_label(&font, size, &text, effect, (width, height), settings);
+    let positions = watermark::placements(
+        settings.position,
+        settings.margin,
+        (width, height),
+        label.dimensions(),
+    );
+
+    debug!(
+        "Rendering {:.1}px caption ({}x{}) at {} position(s)",
+        size,
+        label.width(),
+        label.height(),
+        positions.len()
+    );
+
+    for (x, y) in positions {
+        image::imageops::overlay(&mut canvas, &label, x as i64, y as i64);
+    }
+
+    DynamicImage::ImageRgba8(canvas)
+}
+
+/// Advance width of each line of text
+fn line_widths(font: &FontRef, size: f32, text: &str) -> Vec<f32> {
+    let font = font.as_scaled(PxScale::from(size));
+    text.lines()
+        .map(|line| {
+            let mut width = 0.0;
+            let mut previous = None;
+            for c in line.chars() {
+                let id = font.glyph_id(c);
+                if let Some(previous) = previous {
+                    width += font.kern(previous, id);
+                }
+                width += font.h_advance(id);
+                previous = Some(id);
+            }
+            width
+        })
+        .collect()
+}
+
+/// Height of `lines` lines of text, without the gap after the last one
+fn text_height(font: &FontRef, size: f32, lines: usize) -> f32 {
+    let font = font.as_scaled(PxScale::from(size));
+    (font.height() + font.line_gap()) * lines as f32 - font.line_gap()
+}
+
+/// Draw the text with its shadow and outline onto a transparent label, no
+/// larger than `max_size`. Lines are aligned towards the side of the image
+/// the label sits on.
+fn render_label(
+    font: &FontRef,
+    size: f32,
+    text: &str,
+    effect: u32,
+    (max_width, max_height): (u32, u32),
+    settings: &CaptionSettings,
+) -> RgbaImage {
+    let scaled = font.as_scaled(PxScale::from(size));
+    let widths = line_widths(font, size, text);
+    let text_width = widths.iter().copied().fold(0.0, f32::max);
+    let line_height = scaled.height() + scaled.line_gap();
+
+    // Room for the outline on every side plus the shadow's offset, clipped
+    // to the image
+    let padding = 2 * effect;
+    let mut coverage = Coverage::new(
+        (text_width.ceil() as u32 + 2 * padding).min(max_width),
+        (text_height(font, size, widths.len()).ceil() as u32 + 2 * padding).min(max_height),
+    );
+
+    let align = match settings.position {
+        WatermarkPosition::TopRight | WatermarkPosition::BottomRight => 1.0,
+        WatermarkPosition::Center => 0.5,
+        _ => 0.0,
+    };
+
+    for (index, (line, line_width)) in text.lines().zip(&widths).enumerate() {
+        let baseline = padding as f32 + scaled.ascent() + index as f32 * line_height;
+        let mut caret = padding as f32 + (text_width - line_width) * align;
+        let mut previous = None;
+
+        for c in line.chars() {
+            let id = scaled.glyph_id(c);
+            if let Some(previous) = previous {
+                caret += scaled.kern(previous, id);
+            }
+            let glyph = id.with_scale_and_position(size, point(caret, baseline));
+            caret += scaled.h_advance(id);
+            previous = Some(id);
+
+            let Some(outlined) = font.outline_glyph(glyph) else {
+                continue;
+            };
+            let bounds = outlined.px_bounds();
+            outlined.draw(|x, y, value| {
+                let x = bounds.min.x as i64 + x as i64;
+                let y = bounds.min.y as i64 + y as i64;
+                if x >= 0 && y >= 0 && x < coverage.width as i64 && y < coverage.height as i64 {
+                    let index = coverage.index(x as u32, y as u32);
+                    coverage.values[index] = (coverage.values[index] + value).min(1.0);
+                }
+            });
+        }
+    }
+
+    // The shadow follows the outline when there is one
+    let outlined = match settings.outline {
+        Some(_) => coverage.dilated(effect),
+        None => coverage.clone(),
+    };
+
+    let mut label = RgbaImage::new(coverage.width, coverage.height);
+    if let Some(shadow) = settings.shadow {
+        blend(&mut label, &outlined.shifted(effect), shadow);
+    }
+    if let Some(outline) = settings.outline {
+        blend(&mut label, &outlined, outline);
+    }
+    blend(&mut label, &coverage, settings.color);
+
+    label
+}
+
+/// Paint `color` over the label wherever the coverage is set
+fn blend(label: &mut RgbaImage, coverage: &Coverage, color: [u8; 4]) {
+    for (pixel, value) in label.pixels_mut().zip(&coverage.values) {
+        let source_alpha = value * color[3] as f32 / 255.0;
+        if source_alpha <= 0.0 {
+            continue;
+        }
+
+        let Rgba(destination) = *pixel;
+        let destination_alpha = destination[3] as f32 / 255.0;
+        let alpha = source_alpha + destination_alpha * (1.0 - source_alpha);
+
+        let mut blended = [0u8; 4];
+        for channel in 0..3 {
+            let value = (color[channel] as f32 * source_alpha
+                + destination[channel] as f32 * destination_alpha * (1.0 - source_alpha))
+                / alpha;
+            blended[channel] = value.round().clamp(0.0, 255.0) as u8;
+        }
+        blended[3] = (alpha * 255.0).round() as u8;
+        *pixel = Rgba(blended);
+    }
+}
@@ -0,0 +1,137 @@
+use image::{DynamicImage, ImageBuffer};
+use moxcms::{
+    ColorProfile, DataColorSpace, Layout, Transform16BitExecutor, Transform8BitExecutor,
+    TransformExecutor, TransformOptions, Xyzd,
+};
+use tracing::{debug, warn};
+
+use crate::utils;
+
+// How far a profile's primaries may drift from sRGB's and still be treated as
+// sRGB - covers the rounding in the many slightly different sRGB profiles
+const SRGB_COLORANT_TOLERANCE: f64 = 0.002;
+
+/// Converts pixels described by an embedded ICC profile to sRGB
+pub struct SrgbConverter {
+    gray: bool,
+    transform_8bit: Box<Transform8BitExecutor>,
+    transform_16bit: Box<Transform16BitExecutor>,
+}
+
+impl SrgbConverter {
+    /// Build a converter for an ICC profile. Returns `None` when the profile is
+    /// already sRGB, or when it can't be read or converted from, in which case
+    /// the pixels are used as they are.
+    pub fn new(icc: &[u8]) -> Option<Self> {
+        let profile = match ColorProfile::new_from_slice(icc) {
+            Ok(profile) => profile,
+            Err(e) => {
+                warn!("Ignoring unreadable ICC profile: {:?}", e);
+                return None;
+            }
+        };
+
+        let gray = match profile.color_space {
+            DataColorSpace::Rgb if is_srgb(&profile) => return None,
+            DataColorSpace::Rgb => false,
+            DataColorSpace::Gray => true,
+            other => {
+                // The decoders already hand CMYK and friends over as RGB
+                debug!("Not converting ICC profile for {:?} data", other);
+                return None;
+            }
+        };
+
+        // Gray profiles map onto the sRGB gray axis, so gray images stay gray
+        let layout = if gray {
+            Layout::GrayAlpha
+        } else {
+            Layout::Rgba
+        };
+
+        let srgb = ColorProfile::new_srgb();
+        let options = TransformOptions::default();
+        let transforms = profile
+            .create_transform_8bit(layout, &srgb, layout, options)
+            .and_then(|transform_8bit| {
+                profile
+                    .create_transform_16bit(layout, &srgb, layout, options)
+                    .map(|transform_16bit| (transform_8bit, transform_16bit))
+            });
+
+        match transforms {
+            Ok((transform_8bit, transform_16bit)) => Some(Self {
+                gray,
+                transform_8bit,
+                transform_16bit,
+            }),
+            Err(e) => {
+                warn!("Can't convert ICC profile to sRGB: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Convert an image to sRGB. High bit depth images keep 16 bits per
+    /// channel; everything comes out with an alpha channel.
+    pub fn convert(&self, img: DynamicImage) -> DynamicImage {
+        let (width, height) = (img.width(), img.height());
+        let high_depth = utils::is_high_depth(&img);
+
+        let converted = match (high_depth, self.gray) {
+            (false, false) => transform(&*self.transform_8bit, img.to_rgba8().into_raw())
+                .and_then(|raw| ImageBuffer::from_raw(width, height, raw))
+                .map(DynamicImage::ImageRgba8),
+            (false, true) => transform(&*self.transform_8bit, img.to_luma_alpha8().into_raw())
+                .and_then(|raw| ImageBuffer::from_raw(width, height, raw))
+                .map(DynamicImage::ImageLumaA8),
+            (true, false) => transform(&*self.transform_16bit, img.to_rgba16().into_raw())
+                .and_then(|raw| ImageBuffer::from_raw(width, height, raw))
+                .map(DynamicImage::ImageRgba16),
+            (true, true) => transform(&*self.transform_16bit, img.to_luma_alpha16().into_raw())
+                .and_then(|raw| ImageBuffer::from_raw(width, height, raw))
+                .map(DynamicImage::ImageLumaA16),
+        };
+
+        match converted {
+            Some(converted) => converted,
+            None => {
+                warn!("ICC conversion failed, keeping the original pixels");
+                img
+            }
+        }
+    }
+}
+
+/// Run a transform over raw samples; source and target share a layout
+fn transform<T: Copy + Default>(
+    executor: &dyn TransformExecutor<T>,
+    source: Vec<T>,
+) -> Option<Vec<T>> {
+    let mut target = vec![T::default(); source.len()];
+    executor.transform(&source, &mut target).ok()?;
+    Some(target)
+}
+
+/// Whether an RGB profile uses the sRGB primaries. Only the primaries are
+/// compared, profiles with custom LUTs are always converted.
+fn is_srgb(profile: &ColorProfile) -> bool {
+    if profile.lut_a_to_b_perceptual.is_some() || profile.lut_a_to_b_colorimetric.is_some() {
+        return false;
+    }
+
+    let srgb = ColorProfile::new_srgb();
+    [
+        (&profile.red_colorant, &srgb.red_colorant),
+        (&profile.green_colorant, &srgb.green_colorant),
+        (&profile.blue_colorant, &srgb.blue_colorant),
+    ]
+    .iter()
+    .all(|(a, b)| colorant_close(a, b))
+}
+
+fn colorant_close(a: &Xyzd, b: &Xyzd) -> bool {
+    (a.x - b.x).abs() < SRGB_COLORANT_TOLERANCE
+        && (a.y - b.y).abs() < SRGB_COLORANT_TOLERANCE
+        && (a.z - b.z).abs() < SRGB_COLORANT_TOLERANCE
+}
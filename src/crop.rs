@@ -0,0 +1,83 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage};
+
+// Smart crop works on a small copy - entropy barely changes with scale and the
+// search stays cheap even for large photos
+const ANALYSIS_SIZE: u32 = 256;
+
+// Number of positions tried along each axis the crop window can move on
+const CROP_POSITIONS: u32 = 32;
+
+/// Top-left corner of the `width`x`height` window with the most detail, scored
+/// by the Shannon entropy of its luma histogram. Flat images keep the centered
+/// window.
+pub fn smart_crop_origin(img: &DynamicImage, width: u32, height: u32) -> (u32, u32) {
+    let free_x = img.width().saturating_sub(width);
+    let free_y = img.height().saturating_sub(height);
+    if free_x == 0 && free_y == 0 {
+        return (0, 0);
+    }
+
+    let scale = f64::min(
+        1.0,
+        ANALYSIS_SIZE as f64 / img.width().max(img.height()) as f64,
+    );
+    let scaled = |value: u32| ((value as f64 * scale).round() as u32).max(1);
+
+    let analysis = img
+        .resize_exact(
+            scaled(img.width()),
+            scaled(img.height()),
+            FilterType::Triangle,
+        )
+        .to_luma8();
+    let window_width = scaled(width).min(analysis.width());
+    let window_height = scaled(height).min(analysis.height());
+    let analysis_free_x = analysis.width() - window_width;
+    let analysis_free_y = analysis.height() - window_height;
+
+    // Start from the center so it wins any tie
+    let mut best = (analysis_free_x / 2, analysis_free_y / 2);
+    let mut best_score = entropy(&analysis, best.0, best.1, window_width, window_height);
+
+    for x in positions(analysis_free_x) {
+        for y in positions(analysis_free_y) {
+            let score = entropy(&analysis, x, y, window_width, window_height);
+            if score > best_score {
+                best = (x, y);
+                best_score = score;
+            }
+        }
+    }
+
+    // Map the winner back onto the full-size image
+    let origin_x = ((best.0 as f64 / scale).round() as u32).min(free_x);
+    let origin_y = ((best.1 as f64 / scale).round() as u32).min(free_y);
+    (origin_x, origin_y)
+}
+
+/// Evenly spaced offsets from 0 to `free`, both ends included
+fn positions(free: u32) -> impl Iterator<Item = u32> {
+    let steps = CROP_POSITIONS.min(free).max(1);
+    (0..=steps).map(move |step| free * step / steps)
+}
+
+/// Shannon entropy of the luma histogram of a window, in bits
+fn entropy(img: &GrayImage, x: u32, y: u32, width: u32, height: u32) -> f64 {
+    let mut histogram = [0u32; 256];
+    for dy in 0..height {
+        for dx in 0..width {
+            histogram[img.get_pixel(x + dx, y + dy).0[0] as usize] += 1;
+        }
+    }
+
+    let total = (width * height) as f64;
+    histogram
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
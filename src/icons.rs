@@ -0,0 +1,173 @@
+use serde_json::json;
+
+use crate::palette::Palette;
+
+/// Sizes packed into `favicon.ico`
+pub const ICO_SIZES: [u32; 3] = [16, 32, 48];
+
+// Maskable icons keep the artwork inside the central 80% that launchers
+// never crop away
+pub const MASKABLE_SAFE_ZONE: f64 = 0.8;
+
+/// How a non-square image is made square
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IconFit {
+    /// Shrink to fit and fill the rest with transparency
+    Pad,
+    /// Fill the square and crop the overflow
+    Cover,
+}
+
+impl IconFit {
+    /// Parse a fit as sent by the client
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "pad" | "contain" => Some(IconFit::Pad),
+            "cover" | "crop" => Some(IconFit::Cover),
+            _ => None,
+        }
+    }
+}
+
+/// How each PNG icon is drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IconStyle {
+    /// Keeps the image's transparency
+    Transparent,
+    /// Flattened onto the background color, as iOS shows transparency as black
+    Opaque,
+    /// Opaque, with the artwork shrunk into the maskable safe zone
+    Maskable,
+}
+
+/// A PNG icon of the set
+pub struct IconSpec {
+    pub filename: &'static str,
+    pub size: u32,
+    pub style: IconStyle,
+    /// Listed in the web manifest
+    pub in_manifest: bool,
+}
+
+/// The PNG favicons, apple-touch-icon and Android/PWA icons
+pub const PNG_ICONS: [IconSpec; 6] = [
+    IconSpec {
+        filename: "favicon-16x16.png",
+        size: 16,
+        style: IconStyle::Transparent,
+        in_manifest: false,
+    },
+    IconSpec {
+        filename: "favicon-32x32.png",
+        size: 32,
+        style: IconStyle::Transparent,
+        in_manifest: false,
+    },
+    IconSpec {
+        filename: "apple-touch-icon.png",
+        size: 180,
+        style: IconStyle::Opaque,
+        in_manifest: false,
+    },
+    IconSpec {
+        filename: "android-chrome-192x192.png",
+        size: 192,
+        style: IconStyle::Transparent,
+        in_manifest: true,
+    },
+    IconSpec {
+        filename: "android-chrome-512x512.png",
+        size: 512,
+        style: IconStyle::Transparent,
+        in_manifest: true,
+    },
+    IconSpec {
+        filename: "maskable-icon-512x512.png",
+        size: 512,
+        style: IconStyle::Maskable,
+        in_manifest: true,
+    },
+];
+
+pub const ICO_FILENAME: &str = "favicon.ico";
+pub const MANIFEST_FILENAME: &str = "site.webmanifest";
+
+/// Per-request settings for the icon set
+#[derive(Debug, Clone)]
+pub struct IconSettings {
+    pub fit: IconFit,
+    /// RGBA fill behind the opaque and maskable icons
+    pub background: [u8; 4],
+    /// App name for the web manifest
+    pub name: String,
+    /// Name shown under the home screen icon, `name` when unset
+    pub short_name: Option<String>,
+    /// Browser UI color, left out of the manifest when unset
+    pub theme_color: Option<[u8; 4]>,
+}
+
+impl Default for IconSettings {
+    fn default() -> Self {
+        Self {
+            fit: IconFit::Pad,
+            background: [255, 255, 255, 255],
+            name: String::new(),
+            short_name: None,
+            theme_color: None,
+        }
+    }
+}
+
+fn hex([red, green, blue, _]: [u8; 4]) -> String {
+    Palette::hex([red, green, blue])
+}
+
+/// The `site.webmanifest` listing the Android/PWA icons
+pub fn manifest(settings: &IconSettings) -> String {
+    let icons: Vec<_> = PNG_ICONS
+        .iter()
+        .filter(|icon| icon.in_manifest)
+        .map(|icon| {
+            let mut entry = json!({
+                "src": format!("/{}", icon.filename),
+                "sizes": format!("{0}x{0}", icon.size),
+                "type": "image/png",
+            });
+            if icon.style == IconStyle::Maskable {
+                entry["purpose"] = json!("maskable");
+            }
+            entry
+        })
+        .collect();
+
+    let mut manifest = json!({
+        "name": settings.name,
+        "short_name": settings.short_name.as_deref().unwrap_or(&settings.name),
+        "icons": icons,
+        "background_color": hex(settings.background),
+        "display": "standalone",
+    });
+    if let Some(theme_color) = settings.theme_color {
+        manifest["theme_color"] = json!(hex(theme_color));
+    }
+
+    serde_json::to_string_pretty(&manifest).unwrap_or_default()
+}
+
+/// The `<head>` tags for the set, assuming it's served from the site root
+pub fn html(settings: &IconSettings) -> String {
+    let mut tags = vec![
+        format!(r#"<link rel="icon" href="/{}" sizes="any">"#, ICO_FILENAME),
+        r#"<link rel="icon" type="image/png" sizes="32x32" href="/favicon-32x32.png">"#.to_string(),
+        r#"<link rel="icon" type="image/png" sizes="16x16" href="/favicon-16x16.png">"#.to_string(),
+        r#"<link rel="apple-touch-icon" sizes="180x180" href="/apple-touch-icon.png">"#.to_string(),
+        format!(r#"<link rel="manifest" href="/{}">"#, MANIFEST_FILENAME),
+    ];
+    if let Some(theme_color) = settings.theme_color {
+        tags.push(format!(
+            r#"<meta name="theme-color" content="{}">"#,
+            hex(theme_color)
+        ));
+    }
+    tags.join("\n")
+}
@@ -0,0 +1,129 @@
+use anyhow::{Context, Result};
+use image::io::{Limits, Reader};
+use image::DynamicImage;
+use std::fmt::Display;
+use std::io::Cursor;
+use std::str::FromStr;
+
+// Largest images the server agrees to decode, unless overridden by the
+// environment variables of the same names. A small file can claim huge
+// dimensions, so these are checked against the header before decoding.
+const MAX_DECODE_WIDTH: u32 = 16384;
+const MAX_DECODE_HEIGHT: u32 = 16384;
+const MAX_DECODE_PIXELS: u64 = 100_000_000;
+// Memory a single decode may allocate, all frames of an animation included
+const MAX_DECODE_ALLOC: u64 = 1024 * 1024 * 1024;
+
+/// Caps on what an upload may decode to
+#[derive(Debug, Clone)]
+pub struct DecodeLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Width times height
+    pub max_pixels: u64,
+    /// Bytes the decoder may allocate
+    pub max_alloc: u64,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_width: MAX_DECODE_WIDTH,
+            max_height: MAX_DECODE_HEIGHT,
+            max_pixels: MAX_DECODE_PIXELS,
+            max_alloc: MAX_DECODE_ALLOC,
+        }
+    }
+}
+
+impl DecodeLimits {
+    /// The defaults, with any of the `MAX_DECODE_WIDTH`, `MAX_DECODE_HEIGHT`,
+    /// `MAX_DECODE_PIXELS` and `MAX_DECODE_ALLOC` environment variables
+    /// taking their place
+    pub fn from_env() -> Result<Self> {
+        Ok(Self {
+            max_width: env_or("MAX_DECODE_WIDTH", MAX_DECODE_WIDTH)?,
+            max_height: env_or("MAX_DECODE_HEIGHT", MAX_DECODE_HEIGHT)?,
+            max_pixels: env_or("MAX_DECODE_PIXELS", MAX_DECODE_PIXELS)?,
+            max_alloc: env_or("MAX_DECODE_ALLOC", MAX_DECODE_ALLOC)?,
+        })
+    }
+
+    /// Read the dimensions from the image header and reject images over the
+    /// caps, without decoding any pixels
+    pub fn check(&self, data: &[u8]) -> Result<(u32, u32)> {
+        let (width, height) = Reader::new(Cursor::new(data))
+            .with_guessed_format()?
+            .into_dimensions()
+            .with_context(|| "Failed to read image dimensions")?;
+
+        self.check_dimensions(width, height)?;
+        Ok((width, height))
+    }
+
+    /// Reject dimensions over the caps
+    pub fn check_dimensions(&self, width: u32, height: u32) -> Result<()> {
+        if width > self.max_width || height > self.max_height {
+            anyhow::bail!(
+                "Image is {}x{}, over the {}x{} limit",
+                width,
+                height,
+                self.max_width,
+                self.max_height
+            );
+        }
+
+        let pixels = width as u64 * height as u64;
+        if pixels > self.max_pixels {
+            anyhow::bail!(
+                "Image has {} pixels, over the {} pixel limit",
+                pixels,
+                self.max_pixels
+            );
+        }
+
+        // RGBA at 16 bits per channel is the most any decoder here produces
+        if pixels * 8 > self.max_alloc {
+            anyhow::bail!(
+                "Decoding a {}x{} image could take more than the {} byte limit",
+                width,
+                height,
+                self.max_alloc
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Check the header, then decode with the decoder's own limits set as well
+    pub fn decode(&self, data: &[u8]) -> Result<DynamicImage> {
+        self.check(data)?;
+
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits.max_alloc = Some(self.max_alloc);
+
+        let mut reader = Reader::new(Cursor::new(data)).with_guessed_format()?;
+        reader.limits(limits);
+        Ok(reader.decode()?)
+    }
+}
+
+/// Read a positive number from an environment variable, or use the default
+/// when it isn't set
+fn env_or<T>(name: &str, default: T) -> Result<T>
+where
+    T: FromStr + PartialEq + Default,
+    T::Err: Display,
+{
+    let Ok(value) = std::env::var(name) else {
+        return Ok(default);
+    };
+
+    match value.trim().parse::<T>() {
+        Ok(parsed) if parsed != T::default() => Ok(parsed),
+        Ok(_) => anyhow::bail!("{} must be greater than zero", name),
+        Err(e) => anyhow::bail!("Invalid {} {:?}: {}", name, value, e),
+    }
+}
@@ -1,7 +1,6 @@
-use std::fs::File;
 use std::io::{Cursor, Write};
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -9,7 +8,7 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use axum::{
     body::Full,
     extract::{Multipart, Query, State},
-    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    http::{header, Method, StatusCode},
     response::{Html, IntoResponse, Response},
     routing::{get, post},
     Json, Router,
@@ -18,18 +17,45 @@ use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::services::ServeDir;
-use tracing::{debug, info, Level};
+use tracing::{debug, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 use uuid::Uuid;
 
+mod animation;
+mod cache;
+mod caption;
+mod color;
+mod crop;
+mod icons;
+mod limits;
+mod metadata;
 mod optimizer;
+mod palette;
+mod phash;
+mod placeholder;
+mod quantize;
+mod resample;
+mod sharpen;
+mod ssim;
+mod svg;
+// Shared helpers. Only is_high_depth is called so far; the file name and
+// formatting helpers are kept for the handlers to adopt.
+#[allow(dead_code)]
 mod utils;
+mod watermark;
+
+// Maximum upload size (15MB)
+const MAX_FILE_SIZE: usize = 15 * 1024 * 1024;
 
 // App state shared between routes
 struct AppState {
     temp_dir: PathBuf,
     optimized_dir: PathBuf,
+    watermark_dir: PathBuf,
     rename_counter: AtomicUsize,
+    cache: cache::ResultCache,
+    // Caps on what uploads may decode to, read from the environment at startup
+    limits: limits::DecodeLimits,
 }
 
 impl AppState {
@@ -62,7 +88,7 @@ impl AppState {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 struct OptimizedImage {
     id: String,
     filename: String,
@@ -73,6 +99,84 @@ struct OptimizedImage {
     // Add new fields to track the session
     session_id: String,
     session_path: String,
+    // What the optimizer settled on, absent for renamed files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quality: Option<f32>,
+    // Whether the output fits the requested targetSize
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target_size_met: Option<bool>,
+    // Structural similarity against the resized source, when targetSsim was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssim: Option<f64>,
+    // Number of frames kept, for animated output
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frames: Option<usize>,
+    // Metadata fields and blocks that were dropped from the output
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    metadata_removed: Vec<String>,
+    // Set when optimizing saved nothing and the original bytes were returned
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    kept_original: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kept_original_reason: Option<String>,
+    // Responsive width variants and a <picture> snippet using them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    variants: Vec<ImageVariantInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    html: Option<String>,
+    // Previews to show while the image loads: a BlurHash and a tiny WebP data URI
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blurhash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lqip: Option<String>,
+    // Dominant color and palette as #rrggbb, most common first
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dominant_color: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    palette: Vec<String>,
+    // 64-bit difference hash as hex, and the earlier upload this one resembles
+    #[serde(skip_serializing_if = "Option::is_none")]
+    perceptual_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duplicate_of: Option<DuplicateInfo>,
+    // Whether the result was served from the cache of earlier requests
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    cache_hit: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DuplicateInfo {
+    id: String,
+    filename: String,
+    // Hamming distance between the two hashes, out of 64
+    distance: u32,
+    // "duplicate" or "near_duplicate"
+    similarity: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ImageVariantInfo {
+    width: u32,
+    height: u32,
+    size: u64,
+    filename: String,
+    download_url: String,
+}
+
+#[derive(Debug, Serialize)]
+struct IconSet {
+    session_id: String,
+    session_path: String,
+    files: Vec<ImageVariantInfo>,
+    // Contents of the site.webmanifest written with the icons
+    manifest: String,
+    // <head> tags referencing the set
+    html: String,
+    zip_url: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -102,8 +206,16 @@ async fn main() {
     std::fs::create_dir_all(&temp_dir).expect("Failed to create temp directory");
     std::fs::create_dir_all(&optimized_dir).expect("Failed to create optimized directory");
 
+    // Uploaded watermarks live outside the static tree, they are only used
+    // by name
+    let watermark_dir = std::env::current_dir()
+        .expect("Failed to get current directory")
+        .join("watermarks");
+    std::fs::create_dir_all(&watermark_dir).expect("Failed to create watermark directory");
+
     info!("Temp directory: {:?}", temp_dir);
     info!("Optimized directory: {:?}", optimized_dir);
+    info!("Watermark directory: {:?}", watermark_dir);
 
     // Create static directory for the frontend
     let static_dir = std::env::current_dir()
@@ -123,12 +235,21 @@ async fn main() {
     let script_js = include_str!("../static/script.js");
     std::fs::write(static_dir.join("script.js"), script_js).expect("Failed to write script.js");
 
+    // Optimized outputs kept in memory for repeated uploads (256MB)
+    const RESULT_CACHE_SIZE: usize = 256 * 1024 * 1024;
+
+    let limits = limits::DecodeLimits::from_env().expect("Invalid decode limits");
+    info!("Decode limits: {:?}", limits);
+
     // Create shared state
     let optimized_dir_for_state = optimized_dir.clone();
     let state = Arc::new(AppState {
         temp_dir,
         optimized_dir: optimized_dir_for_state,
+        watermark_dir,
         rename_counter: AtomicUsize::new(0),
+        cache: cache::ResultCache::new(RESULT_CACHE_SIZE),
+        limits,
     });
 
     // Configure CORS
@@ -142,6 +263,11 @@ async fn main() {
         .route("/", get(index_handler))
         .route("/api/optimize", post(optimize_handler))
         .route("/api/rename", post(rename_handler))
+        .route("/api/icons", post(icons_handler))
+        .route(
+            "/api/watermarks",
+            get(list_watermarks_handler).post(upload_watermark_handler),
+        )
         .route("/api/download-zip", get(download_zip_handler))
         .nest_service("/static", ServeDir::new(static_dir))
         .nest_service("/optimized", ServeDir::new(optimized_dir))
@@ -170,6 +296,11 @@ async fn optimize_handler(
     mut multipart: Multipart,
 ) -> Result<Json<Vec<OptimizedImage>>, (StatusCode, String)> {
     let mut results = Vec::new();
+    let mut image_fields = Vec::new();
+    let mut options = optimizer::OptimizationOptions {
+        limits: state.limits.clone(),
+        ..Default::default()
+    };
 
     info!("Starting to process multipart form data for optimization");
 
@@ -193,17 +324,106 @@ async fn optimize_handler(
 
     info!("Using session directory: {}", session_id);
 
-    // Process each field individually using a more resilient approach
+    // First pass: collect the options and the uploaded files, so options can
+    // be sent in any order relative to the files
     while let Ok(Some(field)) = multipart.next_field().await {
-        // Process one field at a time, with separate error handling
-        info!("Processing a new field from multipart form");
+        let field_name = match field.name() {
+            Some(name) => name.to_string(),
+            None => {
+                info!("Field has no name, skipping");
+                continue;
+            }
+        };
+
+        if field_name == "file" || field_name == "files" {
+            let filename = match field.file_name() {
+                Some(name) => name.to_string(),
+                None => {
+                    info!("Missing filename for field: {}", field_name);
+                    continue;
+                }
+            };
+
+            match field.bytes().await {
+                Ok(data) => image_fields.push((filename, data)),
+                Err(e) => info!("Failed to read file data for {}: {}", filename, e),
+            }
+            continue;
+        }
+
+        let value = match field.text().await {
+            Ok(value) => value.trim().to_string(),
+            Err(e) => {
+                info!("Failed to read option field {}: {}", field_name, e);
+                continue;
+            }
+        };
 
-        if let Some(optimized_image) = process_field(field, &state, &session_dir, &session_id).await
+        if !apply_option_field(&mut options, &field_name, &value) {
+            info!("Ignoring field {} with value {:?}", field_name, value);
+        }
+    }
+
+    // Watermarks are referenced by name and loaded once for the whole batch
+    if let Some(name) = options.watermark.name.clone() {
+        options.watermark.image = Some(load_watermark(&state, &name).await?);
+    }
+
+    // Hashes of the images optimized so far, to spot repeated uploads
+    let mut seen: Vec<(u64, String, String)> = Vec::new();
+    // Uploads refused for claiming more pixels than the server decodes
+    let mut rejected = Vec::new();
+
+    // Second pass: optimize each collected image
+    for (filename, data) in image_fields {
+        info!("Processing file: {}", filename);
+
+        // Only the header is read, so oversized images are refused before
+        // anything is decoded. Files that aren't images are left to
+        // process_field.
+        if image::guess_format(&data).is_ok() {
+            if let Err(e) = options.limits.check(&data) {
+                warn!("Rejecting {}: {}", filename, e);
+                rejected.push(format!("{}: {}", filename, e));
+                continue;
+            }
+        }
+
+        let hash = match options.duplicates.mode {
+            phash::DuplicateMode::Off => None,
+            _ => perceptual_hash(&filename, data.clone(), &options.limits).await,
+        };
+        let duplicate = hash.and_then(|hash| find_duplicate(hash, &seen, &options.duplicates));
+
+        if let Some(duplicate) = &duplicate {
+            info!(
+                "{} looks like {} ({}, distance {})",
+                filename, duplicate.filename, duplicate.similarity, duplicate.distance
+            );
+            if options.duplicates.mode == phash::DuplicateMode::Skip
+                && duplicate.similarity == phash::Similarity::Duplicate.as_str()
+            {
+                info!("Skipping duplicate upload: {}", filename);
+                continue;
+            }
+        }
+
+        if let Some(mut optimized_image) =
+            process_field(filename, data, &options, &state, &session_dir, &session_id).await
         {
             info!(
                 "Successfully optimized image: {:?}",
                 optimized_image.filename
             );
+            if let Some(hash) = hash {
+                seen.push((
+                    hash,
+                    optimized_image.id.clone(),
+                    optimized_image.filename.clone(),
+                ));
+                optimized_image.perceptual_hash = Some(format!("{:016x}", hash));
+            }
+            optimized_image.duplicate_of = duplicate;
             results.push(optimized_image);
         }
     }
@@ -214,6 +434,10 @@ async fn optimize_handler(
         session_id
     );
 
+    if results.is_empty() && !rejected.is_empty() {
+        return Err((StatusCode::PAYLOAD_TOO_LARGE, rejected.join("\n")));
+    }
+
     if results.is_empty() {
         return Err((
             StatusCode::BAD_REQUEST,
@@ -224,45 +448,666 @@ async fn optimize_handler(
     Ok(Json(results))
 }
 
-// Process a single field from the multipart form
-async fn process_field(
-    field: axum::extract::multipart::Field<'_>,
-    state: &Arc<AppState>,
-    session_dir: &PathBuf,
-    session_id: &str,
-) -> Option<OptimizedImage> {
-    // Maximum file size (15MB)
-    const MAX_FILE_SIZE: usize = 15 * 1024 * 1024;
+// Difference hash of an upload, `None` when it can't be decoded
+async fn perceptual_hash(
+    filename: &str,
+    data: Bytes,
+    limits: &limits::DecodeLimits,
+) -> Option<u64> {
+    let limits = limits.clone();
+    match tokio::task::spawn_blocking(move || phash::hash_image_data(&data, &limits)).await {
+        Ok(Ok(hash)) => Some(hash),
+        Ok(Err(e)) => {
+            info!("Failed to hash {}: {}", filename, e);
+            None
+        }
+        Err(e) => {
+            info!("Hashing task failed for {}: {}", filename, e);
+            None
+        }
+    }
+}
 
-    // 1. Get field name
-    let field_name = match field.name() {
-        Some(name) => name.to_string(),
-        None => {
-            info!("Field has no name, skipping");
-            return None;
+// The closest earlier image, if it's close enough to count as a duplicate
+fn find_duplicate(
+    hash: u64,
+    seen: &[(u64, String, String)],
+    settings: &phash::DuplicateSettings,
+) -> Option<DuplicateInfo> {
+    let (distance, id, filename) = seen
+        .iter()
+        .map(|(other, id, filename)| (phash::distance(hash, *other), id, filename))
+        .min_by_key(|(distance, _, _)| *distance)?;
+
+    settings.classify(distance).map(|similarity| DuplicateInfo {
+        id: id.clone(),
+        filename: filename.clone(),
+        distance,
+        similarity: similarity.as_str().to_string(),
+    })
+}
+
+// Build a favicon and app icon set from one upload into a new session
+async fn icons_handler(
+    State(state): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<Json<IconSet>, (StatusCode, String)> {
+    let mut settings = icons::IconSettings::default();
+    let mut options = optimizer::OptimizationOptions {
+        filter: image::imageops::FilterType::Lanczos3,
+        limits: state.limits.clone(),
+        ..Default::default()
+    };
+    let mut upload = None;
+
+    while let Ok(Some(field)) = multipart.next_field().await {
+        let field_name = match field.name() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+
+        if field_name == "file" {
+            let filename = field.file_name().unwrap_or("icon").to_string();
+            match field.bytes().await {
+                Ok(data) => upload = Some((filename, data)),
+                Err(e) => info!("Failed to read file data for {}: {}", filename, e),
+            }
+            continue;
         }
+
+        let value = match field.text().await {
+            Ok(value) => value.trim().to_string(),
+            Err(e) => {
+                info!("Failed to read option field {}: {}", field_name, e);
+                continue;
+            }
+        };
+
+        let applied = match field_name.as_str() {
+            "name" => {
+                settings.name = value.clone();
+                true
+            }
+            "shortName" => {
+                settings.short_name = Some(value.clone()).filter(|v| !v.is_empty());
+                true
+            }
+            "themeColor" => match optimizer::color_from_hex(&value) {
+                Some(color) => {
+                    settings.theme_color = Some(color);
+                    true
+                }
+                None => false,
+            },
+            "backgroundColor" => match optimizer::color_from_hex(&value) {
+                Some(color) => {
+                    settings.background = color;
+                    true
+                }
+                None => false,
+            },
+            "iconFit" => match icons::IconFit::from_name(&value) {
+                Some(fit) => {
+                    settings.fit = fit;
+                    true
+                }
+                None => false,
+            },
+            _ => apply_option_field(&mut options, &field_name, &value),
+        };
+        if !applied {
+            info!("Ignoring field {} with value {:?}", field_name, value);
+        }
+    }
+
+    let Some((filename, data)) = upload else {
+        return Err((StatusCode::BAD_REQUEST, "Missing image file".to_string()));
     };
-    info!("Processing field: {}", field_name);
 
-    // We expect files to be sent with field name "file" or "files"
-    if field_name != "file" && field_name != "files" {
-        info!("Skipping field with unexpected name: {}", field_name);
-        return None;
+    if data.len() > MAX_FILE_SIZE {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "File too large: {} bytes (max: {} bytes)",
+                data.len(),
+                MAX_FILE_SIZE
+            ),
+        ));
     }
 
-    // 2. Get filename
-    let filename = match field.file_name() {
-        Some(name) => {
-            info!("Field has filename: {}", name);
-            name.to_string()
+    match image::guess_format(&data) {
+        Ok(_) => {
+            if let Err(e) = options.limits.check(&data) {
+                warn!("Rejecting {}: {}", filename, e);
+                return Err((
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!("{}: {}", filename, e),
+                ));
+            }
         }
-        None => {
-            info!("Missing filename for field: {}", field_name);
-            return None;
+        Err(_) if svg::is_svg(&data) => {}
+        Err(_) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("{} is not a recognized image format", filename),
+            ));
         }
+    }
+
+    let session_dir = state.create_session_dir("icons").await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to create session directory: {}", e),
+        )
+    })?;
+    let session_id = session_dir
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or("unknown_session")
+        .to_string();
+
+    info!(
+        "Generating icons for {} in session {}",
+        filename, session_id
+    );
+
+    let temp_path = state.temp_dir.join(Uuid::new_v4().to_string());
+    tokio::fs::write(&temp_path, &data).await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to write temp file: {}", e),
+        )
+    })?;
+
+    let generated = optimizer::generate_icons(
+        temp_path.as_path(),
+        session_dir.as_path(),
+        &settings,
+        &options,
+    )
+    .await;
+    if let Err(e) = tokio::fs::remove_file(&temp_path).await {
+        info!("Failed to remove temp file: {}", e);
+    }
+    let written = generated.map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Failed to generate icons for {}: {}", filename, e),
+        )
+    })?;
+
+    let manifest = icons::manifest(&settings);
+    let manifest_path = session_dir.join(icons::MANIFEST_FILENAME);
+    tokio::fs::write(&manifest_path, &manifest)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to write web manifest: {}", e),
+            )
+        })?;
+
+    let files = written
+        .into_iter()
+        .filter_map(|icon| {
+            let filename = icon.path.file_name()?.to_str()?.to_string();
+            Some(ImageVariantInfo {
+                width: icon.width,
+                height: icon.height,
+                size: icon.size,
+                download_url: format!("/optimized/{}/{}", session_id, filename),
+                filename,
+            })
+        })
+        .collect();
+
+    Ok(Json(IconSet {
+        session_path: session_id.clone(),
+        files,
+        manifest,
+        html: icons::html(&settings),
+        zip_url: format!("/api/download-zip?session={}", session_id),
+        session_id,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+struct WatermarkAsset {
+    name: String,
+    width: u32,
+    height: u32,
+}
+
+// Watermark names end up in file paths, so they are kept to a safe alphabet
+fn is_valid_watermark_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// Store an uploaded watermark as PNG under a name the optimize form can use.
+// The name comes from the `name` field, or the file name without extension.
+async fn upload_watermark_handler(
+    State(state): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<Json<WatermarkAsset>, (StatusCode, String)> {
+    // Logos are small, anything bigger is most likely a mistake
+    const MAX_WATERMARK_SIZE: usize = 5 * 1024 * 1024;
+
+    let mut name = None;
+    let mut upload = None;
+
+    while let Ok(Some(field)) = multipart.next_field().await {
+        match field.name() {
+            Some("name") => name = field.text().await.ok().map(|v| v.trim().to_string()),
+            Some("file") => {
+                let stem = field
+                    .file_name()
+                    .and_then(|f| Path::new(f).file_stem())
+                    .and_then(|s| s.to_str())
+                    .map(|s| s.to_string());
+                if let Ok(data) = field.bytes().await {
+                    upload = Some((stem, data));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let Some((stem, data)) = upload else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Missing watermark file".to_string(),
+        ));
     };
 
-    // 3. Check if the file is an image by extension
+    let name = name.filter(|n| !n.is_empty()).or(stem).unwrap_or_default();
+    if !is_valid_watermark_name(&name) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Invalid watermark name {:?}, use letters, digits, - and _",
+                name
+            ),
+        ));
+    }
+
+    if data.len() > MAX_WATERMARK_SIZE {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "Watermark too large: {} bytes (max: {} bytes)",
+                data.len(),
+                MAX_WATERMARK_SIZE
+            ),
+        ));
+    }
+
+    let logo = state.limits.decode(&data).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Watermark is not a readable image: {}", e),
+        )
+    })?;
+
+    let mut png = Vec::new();
+    logo.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to encode watermark: {}", e),
+            )
+        })?;
+
+    let path = state.watermark_dir.join(format!("{}.png", name));
+    tokio::fs::write(&path, png).await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to store watermark: {}", e),
+        )
+    })?;
+
+    info!("Stored watermark {:?} at {:?}", name, path);
+
+    Ok(Json(WatermarkAsset {
+        name,
+        width: logo.width(),
+        height: logo.height(),
+    }))
+}
+
+// List the names of the uploaded watermarks
+async fn list_watermarks_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<String>>, (StatusCode, String)> {
+    let mut entries = tokio::fs::read_dir(&state.watermark_dir)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to read watermark directory: {}", e),
+            )
+        })?;
+
+    let mut names = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) == Some("png") {
+            if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                names.push(stem.to_string());
+            }
+        }
+    }
+
+    names.sort();
+    Ok(Json(names))
+}
+
+// Load an uploaded watermark by name
+async fn load_watermark(
+    state: &AppState,
+    name: &str,
+) -> Result<Arc<image::RgbaImage>, (StatusCode, String)> {
+    let not_found = || {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Unknown watermark {:?}", name),
+        )
+    };
+
+    if !is_valid_watermark_name(name) {
+        return Err(not_found());
+    }
+
+    let data = tokio::fs::read(state.watermark_dir.join(format!("{}.png", name)))
+        .await
+        .map_err(|_| not_found())?;
+
+    let logo = state.limits.decode(&data).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to decode watermark {:?}: {}", name, e),
+        )
+    })?;
+
+    Ok(Arc::new(logo.into_rgba8()))
+}
+
+// Apply a single optimization option sent as a form field, returning false
+// when the field is unknown or its value can't be parsed
+fn apply_option_field(
+    options: &mut optimizer::OptimizationOptions,
+    name: &str,
+    value: &str,
+) -> bool {
+    match name {
+        "quality" => match value.parse::<f32>() {
+            Ok(quality) if (0.0..=100.0).contains(&quality) => options.quality = quality,
+            _ => return false,
+        },
+        "maxWidth" => match value.parse::<u32>() {
+            Ok(width) if width > 0 => options.max_width = width,
+            _ => return false,
+        },
+        "maxHeight" => match value.parse::<u32>() {
+            Ok(height) if height > 0 => options.max_height = height,
+            _ => return false,
+        },
+        "format" => match optimizer::OutputFormat::from_name(value) {
+            Some(format) => {
+                options.format = format;
+                options.format_required = true;
+            }
+            None => return false,
+        },
+        "preservePng" => options.preserve_png = parse_bool(value),
+        "mode" => match optimizer::ResizeMode::from_name(value) {
+            Some(mode) => options.resize_mode = mode,
+            None => return false,
+        },
+        "linearLight" => options.linear_light = parse_bool(value),
+        "sharpen" => match optimizer::SharpenMode::from_name(value) {
+            Some(mode) => options.sharpen.mode = mode,
+            None => return false,
+        },
+        "sharpenAmount" => match value.parse::<f32>() {
+            Ok(amount) if (0.0..=5.0).contains(&amount) => options.sharpen.amount = Some(amount),
+            _ => return false,
+        },
+        "sharpenRadius" => match value.parse::<f32>() {
+            Ok(radius) if radius > 0.0 && radius <= 10.0 => options.sharpen.radius = radius,
+            _ => return false,
+        },
+        "sharpenThreshold" => match value.parse::<u8>() {
+            Ok(threshold) => options.sharpen.threshold = threshold,
+            Err(_) => return false,
+        },
+        "watermark" => match is_valid_watermark_name(value) {
+            true => options.watermark.name = Some(value.to_string()),
+            false => return false,
+        },
+        "watermarkPosition" => match watermark::WatermarkPosition::from_name(value) {
+            Some(position) => options.watermark.position = position,
+            None => return false,
+        },
+        // A margin wider than any image we decode can only overflow
+        "watermarkMargin" => match value.parse::<u32>() {
+            Ok(margin) if margin <= options.limits.max_width.max(options.limits.max_height) => {
+                options.watermark.margin = margin
+            }
+            _ => return false,
+        },
+        "watermarkOpacity" => match value.parse::<f32>() {
+            Ok(opacity) if (0.0..=1.0).contains(&opacity) => options.watermark.opacity = opacity,
+            _ => return false,
+        },
+        "watermarkScale" => match value.parse::<f32>() {
+            Ok(scale) if scale > 0.0 && scale <= 1.0 => options.watermark.scale = scale,
+            _ => return false,
+        },
+        "caption" => options.caption.text = Some(value.to_string()),
+        "captionSize" => match value.parse::<f32>() {
+            Ok(size) if size > 0.0 => options.caption.size = Some(size),
+            _ => return false,
+        },
+        "captionColor" => match optimizer::color_from_hex(value) {
+            Some(color) => options.caption.color = color,
+            None => return false,
+        },
+        "captionShadow" => match optimizer::color_from_hex(value) {
+            Some(color) => options.caption.shadow = Some(color),
+            None => return false,
+        },
+        "captionOutline" => match optimizer::color_from_hex(value) {
+            Some(color) => options.caption.outline = Some(color),
+            None => return false,
+        },
+        "captionPosition" => match watermark::WatermarkPosition::from_name(value) {
+            Some(watermark::WatermarkPosition::Tiled) | None => return false,
+            Some(position) => options.caption.position = position,
+        },
+        "captionMargin" => match value.parse::<u32>() {
+            Ok(margin) if margin <= options.limits.max_width.max(options.limits.max_height) => {
+                options.caption.margin = margin
+            }
+            _ => return false,
+        },
+        "duplicates" => match phash::DuplicateMode::from_name(value) {
+            Some(mode) => options.duplicates.mode = mode,
+            None => return false,
+        },
+        "duplicateThreshold" => match value.parse::<u32>() {
+            Ok(threshold) if threshold <= 64 => options.duplicates.threshold = threshold,
+            _ => return false,
+        },
+        "nearDuplicateThreshold" => match value.parse::<u32>() {
+            Ok(threshold) if threshold <= 64 => options.duplicates.near_threshold = threshold,
+            _ => return false,
+        },
+        "svgWidth" => match value.parse::<u32>() {
+            Ok(width) if width > 0 => options.svg.width = Some(width),
+            _ => return false,
+        },
+        "svgDpi" => match value.parse::<f32>() {
+            Ok(dpi) if dpi > 0.0 => options.svg.dpi = Some(dpi),
+            _ => return false,
+        },
+        "smartCrop" => options.smart_crop = parse_bool(value),
+        "padColor" => match optimizer::color_from_hex(value) {
+            Some(color) => options.pad_color = color,
+            None => return false,
+        },
+        "filter" => match optimizer::filter_from_name(value) {
+            Some(filter) => options.filter = filter,
+            None => return false,
+        },
+        "metadata" => match optimizer::MetadataPolicy::from_name(value) {
+            Some(policy) => options.metadata = policy,
+            None => return false,
+        },
+        "stripGps" => options.strip_gps = parse_bool(value),
+        "neverLarger" => options.never_larger = parse_bool(value),
+        "variants" => match parse_widths(value) {
+            Some(widths) => options.variant_widths = widths,
+            None => return false,
+        },
+        "colorProfile" => match optimizer::ColorProfilePolicy::from_name(value) {
+            Some(policy) => options.color_profile = policy,
+            None => return false,
+        },
+        "pngLevel" => match value.parse() {
+            Ok(level) if level <= 6 => options.png.level = level,
+            _ => return false,
+        },
+        "pngZopfli" => options.png.zopfli = parse_bool(value),
+        "pngQuantize" => options.png.quantize = parse_bool(value),
+        "pngColors" => match value.parse::<u16>() {
+            Ok(colors) if (2..=256).contains(&colors) => options.png.colors = colors,
+            _ => return false,
+        },
+        "pngDither" => options.png.dither = parse_bool(value),
+        "frameStep" => match value.parse::<usize>() {
+            Ok(step) if step > 0 => options.frame_step = step,
+            _ => return false,
+        },
+        "maxFps" => match value.parse::<f32>() {
+            Ok(fps) if fps > 0.0 => options.max_fps = Some(fps),
+            _ => return false,
+        },
+        "avifQuality" => match value.parse::<f32>() {
+            Ok(quality) if (0.0..=100.0).contains(&quality) => options.avif.quality = quality,
+            _ => return false,
+        },
+        "avifSpeed" => match value.parse::<u8>() {
+            Ok(speed) if (1..=10).contains(&speed) => options.avif.speed = speed,
+            _ => return false,
+        },
+        "jpegQuality" => match value.parse::<f32>() {
+            Ok(quality) if (1.0..=100.0).contains(&quality) => options.jpeg.quality = quality,
+            _ => return false,
+        },
+        "jpegProgressive" => options.jpeg.progressive = parse_bool(value),
+        "jpegSubsampling" => match optimizer::ChromaSubsampling::from_name(value) {
+            Some(subsampling) => options.jpeg.subsampling = subsampling,
+            None => return false,
+        },
+        "jpegOptimizeHuffman" => options.jpeg.optimize_huffman = parse_bool(value),
+        "jpegBackground" => match optimizer::color_from_hex(value) {
+            Some(color) => options.jpeg.background = color,
+            None => return false,
+        },
+        "targetSize" => match value.parse::<u64>() {
+            Ok(bytes) if bytes > 0 => options.target_size = Some(bytes),
+            _ => return false,
+        },
+        "targetSsim" => match value.parse::<f64>() {
+            Ok(threshold) if (0.0..=1.0).contains(&threshold) => {
+                options.target_ssim = Some(threshold)
+            }
+            _ => return false,
+        },
+        _ => return false,
+    }
+
+    true
+}
+
+// Interpret a checkbox-style form value
+fn parse_bool(value: &str) -> bool {
+    matches!(value.to_lowercase().as_str(), "1" | "true" | "yes" | "on")
+}
+
+// Parse the variant widths: a comma-separated list, or a checkbox value
+// for the default set
+fn parse_widths(value: &str) -> Option<Vec<u32>> {
+    match value.to_lowercase().as_str() {
+        "" | "0" | "false" | "no" | "off" => return Some(Vec::new()),
+        "1" | "true" | "yes" | "on" | "default" => {
+            return Some(optimizer::DEFAULT_VARIANT_WIDTHS.to_vec())
+        }
+        _ => {}
+    }
+
+    value
+        .split(',')
+        .map(|width| match width.trim().parse::<u32>() {
+            Ok(width) if width > 0 => Some(width),
+            _ => None,
+        })
+        .collect()
+}
+
+// Build a ready-to-paste <picture> element: the variants as a WebP srcset,
+// with the optimized image as the fallback
+fn picture_html(
+    variants: &[ImageVariantInfo],
+    fallback_url: &str,
+    width: u32,
+    height: u32,
+) -> String {
+    let srcset = variants
+        .iter()
+        .map(|variant| format!("{} {}w", html_url(&variant.download_url), variant.width))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(
+        "<picture>\n  <source type=\"image/webp\" srcset=\"{}\" sizes=\"100vw\">\n  <img src=\"{}\" width=\"{}\" height=\"{}\" alt=\"\" loading=\"lazy\" decoding=\"async\">\n</picture>",
+        srcset,
+        html_url(fallback_url),
+        width,
+        height
+    )
+}
+
+// Make a URL safe inside a srcset attribute, where whitespace and commas
+// separate the candidates
+fn html_url(url: &str) -> String {
+    url.chars()
+        .map(|c| match c {
+            ' ' => "%20".to_string(),
+            ',' => "%2C".to_string(),
+            '"' => "%22".to_string(),
+            '<' => "%3C".to_string(),
+            '>' => "%3E".to_string(),
+            '&' => "&amp;".to_string(),
+            _ => c.to_string(),
+        })
+        .collect()
+}
+
+// Process a single uploaded file from the multipart form
+async fn process_field(
+    filename: String,
+    data: Bytes,
+    options: &optimizer::OptimizationOptions,
+    state: &Arc<AppState>,
+    session_dir: &Path,
+    session_id: &str,
+) -> Option<OptimizedImage> {
+    // 1. Check if the file is an image by extension
     let extension = std::path::Path::new(&filename)
         .extension()
         .and_then(|ext| ext.to_str())
@@ -270,7 +1115,8 @@ async fn process_field(
 
     match extension {
         Some(ext)
-            if ["jpg", "jpeg", "png", "gif", "webp", "bmp", "tiff"].contains(&ext.as_str()) =>
+            if ["jpg", "jpeg", "png", "gif", "webp", "bmp", "tiff", "svg"]
+                .contains(&ext.as_str()) =>
         {
             // Valid image file extension
             info!("Valid image extension: {}", ext);
@@ -281,28 +1127,23 @@ async fn process_field(
         }
     }
 
-    // 4. Read the file data
-    let data = match field.bytes().await {
-        Ok(bytes) => {
-            let len = bytes.len();
-            if len > MAX_FILE_SIZE {
-                info!(
-                    "File too large: {} bytes (max: {} bytes)",
-                    len, MAX_FILE_SIZE
-                );
-                return None;
-            }
-            info!("Read {} bytes of data", len);
-            bytes
-        }
-        Err(e) => {
-            info!("Failed to read file data: {}", e);
-            return None;
-        }
-    };
+    // 2. Check the file size
+    let len = data.len();
+    if len > MAX_FILE_SIZE {
+        info!(
+            "File too large: {} bytes (max: {} bytes)",
+            len, MAX_FILE_SIZE
+        );
+        return None;
+    }
+    info!("Read {} bytes of data", len);
 
-    // 5. Quick validation of image format
+    // 3. Quick validation of image format; SVGs are rendered to PNG
     let format = match image::guess_format(&data) {
+        Err(_) if svg::is_svg(&data) => {
+            info!("Detected SVG image");
+            image::ImageFormat::Png
+        }
         Ok(format) => {
             info!("Detected image format: {:?}", format);
             format
@@ -313,38 +1154,49 @@ async fn process_field(
         }
     };
 
-    // 6. Generate a unique ID for the image
+    // 4. Work out which container this image ends up in
+    let output_format = options.output_format_for(format);
+
+    // 5. Generate a unique ID for the image
     let id = Uuid::new_v4().to_string();
     info!("Processing file: {} (ID: {})", filename, id);
 
-    // 7. Create a temporary file for the uploaded image
+    // 6. Create a temporary file for the uploaded image
     let temp_path = state.temp_dir.join(&id);
     if let Err(e) = tokio::fs::write(&temp_path, &data).await {
         info!("Failed to write temp file: {}", e);
         return None;
     }
 
-    // 8. Determine optimized filename
+    // 7. Determine optimized filename
     let file_stem = std::path::Path::new(&filename)
         .file_stem()
         .and_then(|s| s.to_str())
         .unwrap_or("image");
 
-    let optimized_filename = format!("{}-optimized.webp", file_stem);
+    let optimized_filename = format!("{}-optimized.{}", file_stem, output_format.extension());
     // Use the session directory instead of the general optimized directory
     let output_path = session_dir.join(&optimized_filename);
 
-    // 9. Get original file size
+    // 8. Get original file size
     let original_size = data.len() as u64;
 
-    // 10. Optimize the image
+    // 9. Optimize the image
     info!("Starting optimization for image ID: {}", id);
-    match optimizer::optimize_image(&temp_path, &output_path).await {
-        Ok(_) => {
+    match optimize_cached(&data, &temp_path, &output_path, options, state).await {
+        Ok((report, cache_hit)) => {
             info!("Optimization successful for image ID: {}", id);
 
-            // 11. Get optimized file size
-            let optimized_size = match tokio::fs::metadata(&output_path).await {
+            // The original may have been kept under its own extension
+            let optimized_filename = report
+                .output_path
+                .file_name()
+                .and_then(|s| s.to_str())
+                .map(|s| s.to_string())
+                .unwrap_or(optimized_filename);
+
+            // 10. Get optimized file size
+            let optimized_size = match tokio::fs::metadata(&report.output_path).await {
                 Ok(metadata) => metadata.len(),
                 Err(e) => {
                     info!("Failed to get metadata for optimized file: {}", e);
@@ -366,6 +1218,41 @@ async fn process_field(
 
             let download_url = format!("/optimized/{}/{}", session_folder_name, optimized_filename);
 
+            // 11. Write the responsive variants from the same upload
+            let mut variants = Vec::new();
+            if !options.variant_widths.is_empty() {
+                match optimizer::generate_variants(
+                    temp_path.as_path(),
+                    session_dir,
+                    file_stem,
+                    options,
+                )
+                .await
+                {
+                    Ok(written) => {
+                        variants = written
+                            .into_iter()
+                            .filter_map(|variant| {
+                                let filename = variant.path.file_name()?.to_str()?.to_string();
+                                Some(ImageVariantInfo {
+                                    width: variant.width,
+                                    height: variant.height,
+                                    size: variant.size,
+                                    download_url: format!(
+                                        "/optimized/{}/{}",
+                                        session_folder_name, filename
+                                    ),
+                                    filename,
+                                })
+                            })
+                            .collect();
+                    }
+                    Err(e) => info!("Failed to generate variants: {}", e),
+                }
+            }
+            let html = (!variants.is_empty())
+                .then(|| picture_html(&variants, &download_url, report.width, report.height));
+
             // 12. Remove temporary file
             if let Err(e) = tokio::fs::remove_file(&temp_path).await {
                 info!("Failed to remove temp file: {}", e);
@@ -381,6 +1268,29 @@ async fn process_field(
                 download_url,
                 session_id: session_id.to_string(),
                 session_path: session_folder_name.to_string(),
+                width: Some(report.width),
+                height: Some(report.height),
+                quality: report.quality,
+                target_size_met: report.target_met,
+                ssim: report.ssim,
+                frames: report.frames,
+                metadata_removed: report.metadata_removed,
+                kept_original: report.kept_original.is_some(),
+                kept_original_reason: report.kept_original,
+                variants,
+                html,
+                blurhash: report.placeholder.as_ref().map(|p| p.blurhash.clone()),
+                lqip: report.placeholder.map(|p| p.lqip),
+                dominant_color: report
+                    .palette
+                    .as_ref()
+                    .map(|p| palette::Palette::hex(p.dominant)),
+                palette: report
+                    .palette
+                    .map(|p| p.colors.into_iter().map(palette::Palette::hex).collect())
+                    .unwrap_or_default(),
+                cache_hit,
+                ..Default::default()
             })
         }
         Err(e) => {
@@ -391,6 +1301,56 @@ async fn process_field(
     }
 }
 
+// Optimize an upload, or write out the stored result of an identical earlier
+// request. Returns the report and whether it came from the cache.
+async fn optimize_cached(
+    data: &Bytes,
+    temp_path: &Path,
+    output_path: &Path,
+    options: &optimizer::OptimizationOptions,
+    state: &AppState,
+) -> anyhow::Result<(optimizer::OptimizationReport, bool)> {
+    let key = {
+        let data = data.clone();
+        let options = options.clone();
+        tokio::task::spawn_blocking(move || cache::key(&data, &options)).await?
+    };
+
+    if let Some(cached) = state.cache.get(&key) {
+        let path = output_path.with_extension(&cached.extension);
+        tokio::fs::write(&path, &cached.data).await?;
+        info!(
+            "Cache hit for {:?}, wrote stored result to {:?}",
+            output_path, path
+        );
+
+        let mut report = cached.report;
+        report.output_path = path;
+        return Ok((report, true));
+    }
+
+    debug!("Cache miss for {:?}", output_path);
+    let report = optimizer::optimize_image(temp_path, output_path, options).await?;
+
+    let data = tokio::fs::read(&report.output_path).await?;
+    let extension = report
+        .output_path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_string();
+    state.cache.insert(
+        key,
+        cache::CachedResult {
+            data,
+            extension,
+            report: report.clone(),
+        },
+    );
+
+    Ok((report, false))
+}
+
 // Add this new handler for renaming images without optimization
 async fn rename_handler(
     State(state): State<Arc<AppState>>,
@@ -508,6 +1468,7 @@ async fn rename_handler(
             download_url,
             session_id: session_id.to_string(),
             session_path: session_folder_name.to_string(),
+            ..Default::default()
         });
     }
 
@@ -724,8 +1685,8 @@ async fn download_zip_handler(
 
 // Helper function to find files in a session directory
 async fn find_files_in_session(
-    session_dir: &PathBuf,
-    requested_files: &Vec<String>,
+    session_dir: &Path,
+    requested_files: &[String],
 ) -> Result<Vec<(PathBuf, String)>, std::io::Error> {
     let mut files = Vec::new();
 
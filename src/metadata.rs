@@ -0,0 +1,647 @@
+use anyhow::{Context, Result};
+use exif::experimental::Writer;
+use exif::{Field, In, Tag};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use image::codecs::jpeg::JpegDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::tiff::TiffDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{DynamicImage, ImageDecoder, ImageFormat};
+use std::io::{Cursor, Read, Write};
+use tracing::{debug, warn};
+
+use crate::optimizer::{MetadataPolicy, OutputFormat};
+
+// Container signatures and identifiers
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+const PNG_ICC_NAME: &[u8] = b"ICC Profile";
+const JPEG_XMP_SIGNATURE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const JPEG_EXIF_SIGNATURE: &[u8] = b"Exif\0\0";
+const JPEG_ICC_SIGNATURE: &[u8] = b"ICC_PROFILE\0";
+// Largest JPEG segment payload; the two length bytes count towards 65535
+const JPEG_MAX_SEGMENT: usize = 65533;
+
+// VP8X feature flags
+const WEBP_FLAG_ICC: u8 = 0x20;
+const WEBP_FLAG_ALPHA: u8 = 0x10;
+const WEBP_FLAG_EXIF: u8 = 0x08;
+const WEBP_FLAG_XMP: u8 = 0x04;
+
+// Names used for the non-EXIF blocks in policies and reports
+pub const ICC_NAME: &str = "ICCProfile";
+const XMP_NAME: &str = "XMP";
+const GPS_NAME: &str = "GPS";
+
+/// Ancillary PNG chunks that affect rendering and survive the oxipng pass;
+/// every other ancillary chunk is stripped and metadata is written back by `embed`
+pub const PNG_RENDERING_CHUNKS: [&str; 4] = ["cICP", "sBIT", "sRGB", "pHYs"];
+
+/// Metadata blocks carried by an image
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    /// TIFF-structured EXIF data, without any container prefix
+    pub exif: Option<Vec<u8>>,
+    /// Embedded ICC color profile
+    pub icc: Option<Vec<u8>>,
+    /// XMP packet
+    pub xmp: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.icc.is_none() && self.xmp.is_none()
+    }
+}
+
+/// Read the EXIF Orientation tag (1-8) from JPEG, PNG, TIFF or WebP data
+pub fn read_orientation(data: &[u8]) -> Option<u32> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(data))
+        .ok()?;
+
+    exif.get_field(Tag::Orientation, In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+}
+
+/// Rotate and flip an image so it displays upright for the given EXIF
+/// orientation
+pub fn apply_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
+    if orientation != 1 {
+        debug!("Applying EXIF orientation {}", orientation);
+    }
+
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// Collect the EXIF, ICC and XMP blocks from an encoded image
+pub fn extract(data: &[u8], format: ImageFormat) -> Metadata {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(data))
+        .ok()
+        .map(|exif| exif.buf().to_vec());
+
+    Metadata {
+        exif,
+        icc: read_icc_profile(data, format),
+        xmp: read_xmp(data, format),
+    }
+}
+
+/// Apply a metadata policy to the EXIF and XMP blocks. Returns the blocks to
+/// write into the output and the names of everything that was removed.
+pub fn apply_policy(
+    source: Metadata,
+    policy: &MetadataPolicy,
+    strip_gps: bool,
+) -> (Metadata, Vec<String>) {
+    let allowed = |name: &str| policy.allows(name);
+
+    let mut removed = Vec::new();
+
+    let exif = source
+        .exif
+        .and_then(|exif| filter_exif(exif, &allowed, strip_gps, &mut removed));
+
+    // XMP can repeat the EXIF GPS fields, so it goes too when GPS is stripped
+    let xmp = match source.xmp {
+        Some(xmp) if allowed(XMP_NAME) && !(strip_gps && contains(&xmp, b"exif:GPS")) => Some(xmp),
+        Some(_) => {
+            removed.push(XMP_NAME.to_string());
+            None
+        }
+        None => None,
+    };
+
+    // The ICC profile describes the pixels, so dropping it means converting
+    // them; the optimizer decides that and reports it
+    let metadata = Metadata {
+        exif,
+        icc: source.icc,
+        xmp,
+    };
+
+    (metadata, removed)
+}
+
+/// Rebuild EXIF data with only the allowed fields, recording the rest as removed
+fn filter_exif(
+    data: Vec<u8>,
+    allowed: &dyn Fn(&str) -> bool,
+    strip_gps: bool,
+    removed: &mut Vec<String>,
+) -> Option<Vec<u8>> {
+    let exif = match exif::Reader::new().read_raw(data) {
+        Ok(exif) => exif,
+        Err(e) => {
+            warn!("Dropping unreadable EXIF data: {}", e);
+            removed.push("EXIF".to_string());
+            return None;
+        }
+    };
+
+    let mut kept: Vec<&Field> = Vec::new();
+    for field in exif.fields() {
+        // The orientation is baked into the pixels, and the thumbnail would
+        // still show the unoptimized image
+        if field.tag == Tag::Orientation || field.ifd_num != In::PRIMARY {
+            continue;
+        }
+
+        let name = field.tag.to_string();
+        let is_gps = field.tag.context() == exif::Context::Gps;
+        let keep = if is_gps {
+            !strip_gps && (allowed(GPS_NAME) || allowed(&name))
+        } else {
+            allowed(&name)
+        };
+
+        if keep {
+            kept.push(field);
+        } else if !removed.contains(&name) {
+            removed.push(name);
+        }
+    }
+
+    if kept.is_empty() {
+        return None;
+    }
+
+    let mut writer = Writer::new();
+    for field in &kept {
+        writer.push_field(field);
+    }
+
+    let mut buffer = Cursor::new(Vec::new());
+    match writer.write(&mut buffer, exif.little_endian()) {
+        Ok(()) => Some(buffer.into_inner()),
+        Err(e) => {
+            warn!("Failed to rewrite EXIF data, dropping it: {}", e);
+            for field in kept {
+                let name = field.tag.to_string();
+                if !removed.contains(&name) {
+                    removed.push(name);
+                }
+            }
+            None
+        }
+    }
+}
+
+/// Whether `embed` can write metadata into this output format
+pub fn can_embed(format: OutputFormat) -> bool {
+    match format {
+        OutputFormat::WebP | OutputFormat::Png | OutputFormat::Jpeg => true,
+        // ravif has no API for EXIF or ICC boxes
+        OutputFormat::Avif => false,
+    }
+}
+
+/// Names of the blocks present, as reported when they can't be written
+pub fn block_names(metadata: &Metadata) -> Vec<String> {
+    let mut names = Vec::new();
+    if metadata.exif.is_some() {
+        names.push("EXIF".to_string());
+    }
+    if metadata.icc.is_some() {
+        names.push(ICC_NAME.to_string());
+    }
+    if metadata.xmp.is_some() {
+        names.push(XMP_NAME.to_string());
+    }
+    names
+}
+
+/// Write metadata blocks into encoded output
+pub fn embed(data: Vec<u8>, format: OutputFormat, metadata: &Metadata) -> Result<Vec<u8>> {
+    if metadata.is_empty() {
+        return Ok(data);
+    }
+
+    match format {
+        OutputFormat::WebP => embed_webp(&data, metadata),
+        OutputFormat::Png => embed_png(&data, metadata),
+        OutputFormat::Jpeg => embed_jpeg(&data, metadata),
+        OutputFormat::Avif => Ok(data),
+    }
+}
+
+/// Read the embedded ICC profile from JPEG, PNG, TIFF or WebP data
+pub fn read_icc_profile(data: &[u8], format: ImageFormat) -> Option<Vec<u8>> {
+    let cursor = Cursor::new(data);
+    match format {
+        ImageFormat::Png => PngDecoder::new(cursor).ok()?.icc_profile(),
+        ImageFormat::Jpeg => JpegDecoder::new(cursor).ok()?.icc_profile(),
+        ImageFormat::WebP => WebPDecoder::new(cursor).ok()?.icc_profile(),
+        ImageFormat::Tiff => TiffDecoder::new(cursor).ok()?.icc_profile(),
+        _ => None,
+    }
+}
+
+fn read_xmp(data: &[u8], format: ImageFormat) -> Option<Vec<u8>> {
+    match format {
+        ImageFormat::Jpeg => jpeg_segments(data)
+            .into_iter()
+            .find(|(marker, payload)| *marker == 0xE1 && payload.starts_with(JPEG_XMP_SIGNATURE))
+            .map(|(_, payload)| payload[JPEG_XMP_SIGNATURE.len()..].to_vec()),
+        ImageFormat::Png => png_chunks(data)
+            .into_iter()
+            .filter(|(name, _)| name == b"iTXt")
+            .find_map(|(_, payload)| read_png_xmp(payload)),
+        ImageFormat::WebP => riff_chunks(data)
+            .into_iter()
+            .find(|(name, _)| name == b"XMP ")
+            .map(|(_, payload)| payload.to_vec()),
+        _ => None,
+    }
+}
+
+/// Read the text of an iTXt chunk if it carries XMP
+fn read_png_xmp(payload: &[u8]) -> Option<Vec<u8>> {
+    let rest = payload.strip_prefix(PNG_XMP_KEYWORD)?.strip_prefix(b"\0")?;
+
+    // Compression flag and method, then the language tag and translated keyword
+    let (&compressed, rest) = rest.split_first()?;
+    let rest = rest.get(1..)?;
+    let rest = &rest[rest.iter().position(|&b| b == 0)? + 1..];
+    let text = &rest[rest.iter().position(|&b| b == 0)? + 1..];
+
+    if compressed == 1 {
+        let mut inflated = Vec::new();
+        ZlibDecoder::new(text).read_to_end(&mut inflated).ok()?;
+        Some(inflated)
+    } else {
+        Some(text.to_vec())
+    }
+}
+
+/// Rebuild a PNG with iCCP, eXIf and XMP iTXt chunks right after IHDR
+fn embed_png(data: &[u8], metadata: &Metadata) -> Result<Vec<u8>> {
+    let chunks = png_chunks(data);
+    if chunks.first().map(|(name, _)| name) != Some(b"IHDR") {
+        anyhow::bail!("PNG output doesn't start with IHDR");
+    }
+
+    let mut output = PNG_SIGNATURE.to_vec();
+    write_png_chunk(&mut output, b"IHDR", chunks[0].1);
+
+    if let Some(icc) = &metadata.icc {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(icc)?;
+        let mut payload = PNG_ICC_NAME.to_vec();
+        payload.extend_from_slice(&[0, 0]);
+        payload.extend_from_slice(&encoder.finish()?);
+        write_png_chunk(&mut output, b"iCCP", &payload);
+    }
+
+    if let Some(exif) = &metadata.exif {
+        write_png_chunk(&mut output, b"eXIf", exif);
+    }
+
+    if let Some(xmp) = &metadata.xmp {
+        // Keyword, uncompressed, no language tag or translated keyword
+        let mut payload = PNG_XMP_KEYWORD.to_vec();
+        payload.extend_from_slice(&[0, 0, 0, 0, 0]);
+        payload.extend_from_slice(xmp);
+        write_png_chunk(&mut output, b"iTXt", &payload);
+    }
+
+    for (name, payload) in &chunks[1..] {
+        // An ICC profile and an sRGB chunk must not both be present
+        if name == b"sRGB" && metadata.icc.is_some() {
+            continue;
+        }
+        write_png_chunk(&mut output, name, payload);
+    }
+
+    Ok(output)
+}
+
+fn write_png_chunk(output: &mut Vec<u8>, name: &[u8; 4], payload: &[u8]) {
+    output.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    output.extend_from_slice(name);
+    output.extend_from_slice(payload);
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(name);
+    hasher.update(payload);
+    output.extend_from_slice(&hasher.finalize().to_be_bytes());
+}
+
+/// Rebuild a WebP as an extended (VP8X) file carrying the metadata chunks
+fn embed_webp(data: &[u8], metadata: &Metadata) -> Result<Vec<u8>> {
+    let features =
+        webp::BitstreamFeatures::new(data).with_context(|| "WebP output can't be parsed")?;
+    let chunks = riff_chunks(data);
+
+    // Keep the flags of an existing VP8X chunk, such as animation
+    let mut flags = match chunks.iter().find(|(name, _)| name == b"VP8X") {
+        Some((_, payload)) => payload.first().copied().unwrap_or(0),
+        None => 0,
+    };
+    if features.has_alpha() {
+        flags |= WEBP_FLAG_ALPHA;
+    }
+    if metadata.icc.is_some() {
+        flags |= WEBP_FLAG_ICC;
+    }
+    if metadata.exif.is_some() {
+        flags |= WEBP_FLAG_EXIF;
+    }
+    if metadata.xmp.is_some() {
+        flags |= WEBP_FLAG_XMP;
+    }
+
+    let mut vp8x = vec![flags, 0, 0, 0];
+    vp8x.extend_from_slice(&(features.width() - 1).to_le_bytes()[..3]);
+    vp8x.extend_from_slice(&(features.height() - 1).to_le_bytes()[..3]);
+
+    // Chunk order: VP8X, ICCP, image data (or ANIM/ANMF), EXIF, XMP
+    let mut body = Vec::new();
+    write_riff_chunk(&mut body, b"VP8X", &vp8x);
+    if let Some(icc) = &metadata.icc {
+        write_riff_chunk(&mut body, b"ICCP", icc);
+    }
+    for (name, payload) in &chunks {
+        if !matches!(name, b"VP8X" | b"ICCP" | b"EXIF" | b"XMP ") {
+            write_riff_chunk(&mut body, name, payload);
+        }
+    }
+    if let Some(exif) = &metadata.exif {
+        write_riff_chunk(&mut body, b"EXIF", exif);
+    }
+    if let Some(xmp) = &metadata.xmp {
+        write_riff_chunk(&mut body, b"XMP ", xmp);
+    }
+
+    let mut output = b"RIFF".to_vec();
+    output.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+    output.extend_from_slice(b"WEBP");
+    output.extend_from_slice(&body);
+    Ok(output)
+}
+
+fn write_riff_chunk(output: &mut Vec<u8>, name: &[u8; 4], payload: &[u8]) {
+    output.extend_from_slice(name);
+    output.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    output.extend_from_slice(payload);
+
+    // Chunks are padded to an even size
+    if payload.len() % 2 == 1 {
+        output.push(0);
+    }
+}
+
+/// Rebuild a JPEG with APP1 EXIF, APP2 ICC and APP1 XMP segments after the
+/// JFIF header. Profiles over 64 KB are split across numbered segments;
+/// EXIF and XMP that don't fit a single segment are dropped.
+fn embed_jpeg(data: &[u8], metadata: &Metadata) -> Result<Vec<u8>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        anyhow::bail!("JPEG output doesn't start with SOI");
+    }
+
+    // Keep SOI and the JFIF APP0 segment in front
+    let mut header_end = 2;
+    if let Some((0xE0, payload)) = jpeg_segments(data).first() {
+        header_end += 4 + payload.len();
+    }
+
+    let mut output = data[..header_end].to_vec();
+
+    if let Some(exif) = &metadata.exif {
+        write_jpeg_segment(&mut output, 0xE1, &[JPEG_EXIF_SIGNATURE, exif]);
+    }
+
+    if let Some(icc) = &metadata.icc {
+        // Signature, then the 1-based chunk number and the chunk count
+        let chunk_size = JPEG_MAX_SEGMENT - JPEG_ICC_SIGNATURE.len() - 2;
+        let count = icc.len().div_ceil(chunk_size);
+        if count > 255 {
+            anyhow::bail!("ICC profile of {} bytes is too large for JPEG", icc.len());
+        }
+        for (index, chunk) in icc.chunks(chunk_size).enumerate() {
+            let numbers = [index as u8 + 1, count as u8];
+            write_jpeg_segment(&mut output, 0xE2, &[JPEG_ICC_SIGNATURE, &numbers, chunk]);
+        }
+    }
+
+    if let Some(xmp) = &metadata.xmp {
+        write_jpeg_segment(&mut output, 0xE1, &[JPEG_XMP_SIGNATURE, xmp]);
+    }
+
+    output.extend_from_slice(&data[header_end..]);
+    Ok(output)
+}
+
+fn write_jpeg_segment(output: &mut Vec<u8>, marker: u8, parts: &[&[u8]]) {
+    let length: usize = parts.iter().map(|part| part.len()).sum();
+    if length > JPEG_MAX_SEGMENT {
+        warn!(
+            "Dropping a {} byte metadata segment, JPEG segments hold at most {} bytes",
+            length, JPEG_MAX_SEGMENT
+        );
+        return;
+    }
+
+    output.extend_from_slice(&[0xFF, marker]);
+    output.extend_from_slice(&(length as u16 + 2).to_be_bytes());
+    for part in parts {
+        output.extend_from_slice(part);
+    }
+}
+
+/// Split a PNG into (name, payload) chunks
+fn png_chunks(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut chunks = Vec::new();
+    if !data.starts_with(PNG_SIGNATURE) {
+        return chunks;
+    }
+
+    let mut offset = PNG_SIGNATURE.len();
+    while offset + 12 <= data.len() {
+        let length = u32::from_be_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ]) as usize;
+        let end = offset + 8 + length;
+        if end + 4 > data.len() {
+            break;
+        }
+
+        let mut name = [0; 4];
+        name.copy_from_slice(&data[offset + 4..offset + 8]);
+        chunks.push((name, &data[offset + 8..end]));
+        offset = end + 4;
+    }
+
+    chunks
+}
+
+/// Split a RIFF (WebP) file into (name, payload) chunks
+fn riff_chunks(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut chunks = Vec::new();
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return chunks;
+    }
+
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let size = u32::from_le_bytes([
+            data[offset + 4],
+            data[offset + 5],
+            data[offset + 6],
+            data[offset + 7],
+        ]) as usize;
+        let end = offset + 8 + size;
+        if end > data.len() {
+            break;
+        }
+
+        let mut name = [0; 4];
+        name.copy_from_slice(&data[offset..offset + 4]);
+        chunks.push((name, &data[offset + 8..end]));
+        offset = end + (size & 1);
+    }
+
+    chunks
+}
+
+/// Split a JPEG into (marker, payload) segments up to the start of scan
+fn jpeg_segments(data: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut segments = Vec::new();
+    let mut offset = 2;
+
+    while offset + 4 <= data.len() && data[offset] == 0xFF {
+        let marker = data[offset + 1];
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+
+        let length = u16::from_be_bytes([data[offset + 2], data[offset + 3]]) as usize;
+        let end = offset + 2 + length;
+        if length < 2 || end > data.len() {
+            break;
+        }
+
+        segments.push((marker, &data[offset + 4..end]));
+        offset = end;
+    }
+
+    segments
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::Value;
+    use image::{ImageOutputFormat, RgbImage};
+
+    const ARTIST: &str = "Jane Doe";
+    const XMP: &[u8] = b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"></x:xmpmeta>";
+
+    fn sample_image() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(16, 8, |x, y| {
+            image::Rgb([x as u8 * 16, y as u8 * 32, 128])
+        }))
+    }
+
+    fn encode(format: ImageOutputFormat) -> Vec<u8> {
+        let mut buffer = Cursor::new(Vec::new());
+        sample_image().write_to(&mut buffer, format).unwrap();
+        buffer.into_inner()
+    }
+
+    fn sample_metadata(icc_size: usize) -> Metadata {
+        let artist = Field {
+            tag: Tag::Artist,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![ARTIST.as_bytes().to_vec()]),
+        };
+        let mut writer = Writer::new();
+        writer.push_field(&artist);
+        let mut exif = Cursor::new(Vec::new());
+        writer.write(&mut exif, false).unwrap();
+
+        Metadata {
+            exif: Some(exif.into_inner()),
+            icc: Some((0..icc_size).map(|i| i as u8).collect()),
+            xmp: Some(XMP.to_vec()),
+        }
+    }
+
+    /// Embed the sample metadata, then check the image still decodes and every
+    /// block reads back unchanged
+    fn assert_round_trip(
+        data: Vec<u8>,
+        output: OutputFormat,
+        format: ImageFormat,
+        icc_size: usize,
+    ) {
+        let metadata = sample_metadata(icc_size);
+        let embedded = embed(data, output, &metadata).unwrap();
+
+        let decoded = image::load_from_memory_with_format(&embedded, format).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (16, 8));
+
+        let exif = exif::Reader::new()
+            .read_from_container(&mut Cursor::new(&embedded))
+            .unwrap();
+        let artist = exif.get_field(Tag::Artist, In::PRIMARY).unwrap();
+        assert_eq!(
+            artist.display_value().to_string(),
+            format!("\"{}\"", ARTIST)
+        );
+
+        assert_eq!(read_icc_profile(&embedded, format), metadata.icc);
+        assert_eq!(read_xmp(&embedded, format), metadata.xmp);
+
+        let extracted = extract(&embedded, format);
+        assert_eq!(extracted.exif, metadata.exif);
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let png = encode(ImageOutputFormat::Png);
+        assert_round_trip(png, OutputFormat::Png, ImageFormat::Png, 3000);
+    }
+
+    #[test]
+    fn webp_round_trip() {
+        let rgb = sample_image().to_rgb8();
+        let webp = webp::Encoder::from_rgb(rgb.as_raw(), 16, 8)
+            .encode(75.0)
+            .to_vec();
+        assert_round_trip(webp, OutputFormat::WebP, ImageFormat::WebP, 3001);
+    }
+
+    #[test]
+    fn jpeg_round_trip() {
+        let jpeg = encode(ImageOutputFormat::Jpeg(90));
+        assert_round_trip(jpeg, OutputFormat::Jpeg, ImageFormat::Jpeg, 3000);
+    }
+
+    #[test]
+    fn jpeg_round_trip_splits_large_profiles() {
+        let jpeg = encode(ImageOutputFormat::Jpeg(90));
+        assert_round_trip(jpeg, OutputFormat::Jpeg, ImageFormat::Jpeg, 150_000);
+    }
+}
@@ -1,12 +1,34 @@
 use anyhow::{Context, Result};
-use image::{GenericImageView, ImageFormat};
-use oxipng::{optimize_from_memory, Options as PngOptions};
-use rayon::prelude::*;
+use image::codecs::ico::{IcoEncoder, IcoFrame};
+use image::codecs::png::{CompressionType, FilterType as PngFilterType, PngEncoder};
+use image::imageops::FilterType;
+use image::{ImageFormat, Rgba, RgbaImage};
+use jpeg_encoder::{ColorType as JpegColorType, Encoder as JpegEncoder, SamplingFactor};
+use oxipng::{optimize_from_memory, Deflaters, Headers, Options as PngOptions};
+use ravif::{Img, RGBA8};
 use std::io::Cursor;
-use std::path::Path;
+use std::num::NonZeroU8;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
-use tracing::{debug, error, info, warn};
-use webp::Encoder;
+use tracing::{debug, info, warn};
+use webp::{Decoder, Encoder};
+
+use crate::animation;
+use crate::caption::{self, CaptionSettings};
+use crate::color::SrgbConverter;
+use crate::crop;
+use crate::icons::{self, IconFit, IconSettings, IconStyle};
+use crate::limits::DecodeLimits;
+use crate::metadata;
+use crate::palette::{self, Palette};
+use crate::phash::DuplicateSettings;
+use crate::placeholder::{self, Placeholder};
+use crate::quantize;
+use crate::resample::Resampler;
+use crate::sharpen;
+use crate::ssim;
+use crate::svg::{self, SvgSettings};
+use crate::watermark::{self, WatermarkSettings};
 
 // Maximum dimensions for optimization - increased for faster processing
 const MAX_WIDTH: u32 = 2048;
@@ -15,9 +37,577 @@ const MAX_HEIGHT: u32 = 2048;
 // Quality settings - adjust for faster processing
 const WEBP_QUALITY: f32 = 75.0; // Slightly lower quality for faster encoding
 const PNG_OPTIMIZATION_LEVEL: u8 = 2; // Lower optimization level for faster processing
+const PNG_MAX_LEVEL: u8 = 6;
+const ZOPFLI_ITERATIONS: u8 = 15;
+const PNG_PALETTE_COLORS: u16 = 256; // Most colors an indexed PNG can hold
+const AVIF_QUALITY: f32 = 60.0; // AVIF holds up at lower quality values than WebP
+const AVIF_SPEED: u8 = 6; // 1 (slowest, smallest) to 10 (fastest)
+const JPEG_QUALITY: f32 = 80.0; // JPEG needs a higher setting than WebP for the same look
+
+// Unsharp mask after downscaling - the blur radius and noise threshold used
+// unless the client sets them, and how the automatic strength grows with each
+// halving of the size
+const SHARPEN_RADIUS: f32 = 0.6;
+const SHARPEN_THRESHOLD: u8 = 2;
+const SHARPEN_STRENGTH_PER_HALVING: f64 = 0.3;
+const SHARPEN_MAX_AUTO_AMOUNT: f64 = 0.8;
+
+// Target size search - the quality floor, how many bisection steps to take,
+// how far to shrink the image at least each time quality alone can't meet the
+// budget, and how many encodes a whole search may run
+const MIN_TARGET_QUALITY: f32 = 5.0;
+const TARGET_QUALITY_STEPS: usize = 6;
+const TARGET_SCALE_STEP: f64 = 0.8;
+const MIN_TARGET_DIMENSION: u32 = 16;
+const MAX_TARGET_ENCODES: usize = 24;
+
+// Perceptual quality search - the WebP quality range searched for the lowest
+// setting that still meets the requested SSIM
+const MIN_SSIM_QUALITY: f32 = 10.0;
+const MAX_SSIM_QUALITY: f32 = 100.0;
+const SSIM_QUALITY_STEPS: usize = 7;
+
+// Widths of the responsive variant set when the client asks for the defaults
+pub const DEFAULT_VARIANT_WIDTHS: [u32; 5] = [320, 640, 1024, 1600, 2048];
+
+/// The container format written by the optimizer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Lossy WebP, the default
+    WebP,
+    /// Lossless PNG run through oxipng
+    Png,
+    /// Lossy AVIF, smaller than WebP for photos but slower to encode
+    Avif,
+    /// Lossy JPEG, for consumers that can't read WebP
+    Jpeg,
+}
+
+impl OutputFormat {
+    /// Parse a format name as sent by the client
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "webp" => Some(OutputFormat::WebP),
+            "png" => Some(OutputFormat::Png),
+            "avif" => Some(OutputFormat::Avif),
+            "jpeg" | "jpg" => Some(OutputFormat::Jpeg),
+            _ => None,
+        }
+    }
+
+    /// File extension for the optimized output
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::WebP => "webp",
+            OutputFormat::Png => "png",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Jpeg => "jpg",
+        }
+    }
+
+    /// Whether input of this format is already in the output container
+    pub fn matches(&self, input: ImageFormat) -> bool {
+        matches!(
+            (self, input),
+            (OutputFormat::WebP, ImageFormat::WebP)
+                | (OutputFormat::Png, ImageFormat::Png)
+                | (OutputFormat::Avif, ImageFormat::Avif)
+                | (OutputFormat::Jpeg, ImageFormat::Jpeg)
+        )
+    }
+}
+
+/// What happens to EXIF/ICC/XMP metadata carried by the input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataPolicy {
+    /// Drop all of it
+    Strip,
+    /// Keep whatever the output container can carry
+    Keep,
+    /// Keep only these EXIF tag names (e.g. `Copyright`, `Artist`), plus
+    /// `XMP` and `GPS` for the whole blocks
+    Whitelist(Vec<String>),
+}
+
+impl MetadataPolicy {
+    /// Parse a policy as sent by the client: `strip`, `keep` or a
+    /// comma-separated whitelist
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "strip" => Some(MetadataPolicy::Strip),
+            "keep" => Some(MetadataPolicy::Keep),
+            _ => {
+                let names: Vec<String> = name
+                    .split(',')
+                    .map(|n| n.trim().to_string())
+                    .filter(|n| !n.is_empty())
+                    .collect();
+
+                if names.is_empty() {
+                    None
+                } else {
+                    Some(MetadataPolicy::Whitelist(names))
+                }
+            }
+        }
+    }
+
+    /// Whether the policy keeps the field or block with this name
+    pub fn allows(&self, name: &str) -> bool {
+        match self {
+            MetadataPolicy::Strip => false,
+            MetadataPolicy::Keep => true,
+            MetadataPolicy::Whitelist(names) => names.iter().any(|n| n.eq_ignore_ascii_case(name)),
+        }
+    }
+}
+
+/// What happens to images with an embedded ICC profile
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorProfilePolicy {
+    /// Convert the pixels to sRGB and drop the profile
+    Srgb,
+    /// Leave the pixels alone and embed the profile in the output, so wide
+    /// gamut images keep their gamut. Formats that can't carry a profile are
+    /// converted to sRGB anyway.
+    Keep,
+}
+
+impl ColorProfilePolicy {
+    /// Parse a policy name as sent by the client
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "srgb" | "convert" => Some(ColorProfilePolicy::Srgb),
+            "keep" | "preserve" => Some(ColorProfilePolicy::Keep),
+            _ => None,
+        }
+    }
+}
+
+/// How images are brought to `max_width` x `max_height`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeMode {
+    /// Shrink to fit inside the box, keeping the aspect ratio; never upscales
+    Fit,
+    /// Stretch to exactly the box size
+    Fill,
+    /// Scale to cover the box and crop the overflow
+    Cover,
+    /// Scale to fit inside the box and pad the rest with `pad_color`
+    Pad,
+}
+
+impl ResizeMode {
+    /// Parse a mode name as sent by the client
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "fit" | "contain" => Some(ResizeMode::Fit),
+            "fill" | "stretch" => Some(ResizeMode::Fill),
+            "cover" | "crop" => Some(ResizeMode::Cover),
+            "pad" => Some(ResizeMode::Pad),
+            _ => None,
+        }
+    }
+}
+
+/// Parse a `#rrggbb` or `#rrggbbaa` color as sent by the client
+pub fn color_from_hex(hex: &str) -> Option<[u8; 4]> {
+    let hex = hex.trim_start_matches('#');
+    if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+        return None;
+    }
+
+    let channel = |index: usize| u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).ok();
+    let alpha = if hex.len() == 8 { channel(3)? } else { 255 };
+    Some([channel(0)?, channel(1)?, channel(2)?, alpha])
+}
+
+/// Parse a resampling filter name as sent by the client
+pub fn filter_from_name(name: &str) -> Option<FilterType> {
+    match name.to_lowercase().as_str() {
+        "nearest" => Some(FilterType::Nearest),
+        "triangle" | "bilinear" => Some(FilterType::Triangle),
+        "catmullrom" | "bicubic" => Some(FilterType::CatmullRom),
+        "gaussian" => Some(FilterType::Gaussian),
+        "lanczos3" | "lanczos" => Some(FilterType::Lanczos3),
+        _ => None,
+    }
+}
+
+/// Settings for the lossless oxipng pass
+#[derive(Debug, Clone)]
+pub struct PngSettings {
+    /// oxipng preset level (0-6)
+    pub level: u8,
+    /// Use the slower zopfli deflater for extra savings
+    pub zopfli: bool,
+    /// Reduce the image to a palette first, trading exact colors for size
+    pub quantize: bool,
+    /// Palette size when quantizing (2-256)
+    pub colors: u16,
+    /// Dither when quantizing so gradients don't band
+    pub dither: bool,
+}
+
+impl Default for PngSettings {
+    fn default() -> Self {
+        Self {
+            level: PNG_OPTIMIZATION_LEVEL,
+            zopfli: false,
+            quantize: false,
+            colors: PNG_PALETTE_COLORS,
+            dither: true,
+        }
+    }
+}
+
+/// Settings for AVIF output
+#[derive(Debug, Clone)]
+pub struct AvifSettings {
+    /// Encoder quality (0-100)
+    pub quality: f32,
+    /// Encoder speed, 1 (slowest, smallest) to 10 (fastest)
+    pub speed: u8,
+}
+
+impl Default for AvifSettings {
+    fn default() -> Self {
+        Self {
+            quality: AVIF_QUALITY,
+            speed: AVIF_SPEED,
+        }
+    }
+}
+
+/// Resolution of the JPEG color channels relative to brightness
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChromaSubsampling {
+    /// Full color resolution, for graphics and text with colored edges
+    Full,
+    /// Half horizontal color resolution
+    Half,
+    /// Half color resolution both ways, the smallest and fine for photos
+    Quarter,
+}
+
+impl ChromaSubsampling {
+    /// Parse a ratio as sent by the client, `4:2:0` or `420`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.replace(':', "").as_str() {
+            "444" => Some(ChromaSubsampling::Full),
+            "422" => Some(ChromaSubsampling::Half),
+            "420" => Some(ChromaSubsampling::Quarter),
+            _ => None,
+        }
+    }
+
+    fn sampling_factor(&self) -> SamplingFactor {
+        match self {
+            ChromaSubsampling::Full => SamplingFactor::R_4_4_4,
+            ChromaSubsampling::Half => SamplingFactor::R_4_2_2,
+            ChromaSubsampling::Quarter => SamplingFactor::R_4_2_0,
+        }
+    }
+}
+
+/// Settings for JPEG output
+#[derive(Debug, Clone)]
+pub struct JpegSettings {
+    /// Encoder quality (1-100)
+    pub quality: f32,
+    /// Write a progressive JPEG, which shows a coarse preview while loading
+    pub progressive: bool,
+    pub subsampling: ChromaSubsampling,
+    /// Build Huffman tables for the image instead of using the standard ones
+    pub optimize_huffman: bool,
+    /// RGB fill behind transparent pixels, as JPEG has no alpha
+    pub background: [u8; 4],
+}
+
+impl Default for JpegSettings {
+    fn default() -> Self {
+        Self {
+            quality: JPEG_QUALITY,
+            progressive: true,
+            subsampling: ChromaSubsampling::Quarter,
+            optimize_huffman: true,
+            background: [255, 255, 255, 255],
+        }
+    }
+}
+
+/// When the unsharp mask runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SharpenMode {
+    /// Never sharpen
+    Off,
+    /// Sharpen downscaled photos, but not graphics (PNG, GIF, BMP, ICO inputs)
+    Auto,
+    /// Sharpen every downscaled image
+    On,
+}
+
+impl SharpenMode {
+    /// Parse a mode as sent by the client, checkbox values included
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "off" | "false" | "no" | "0" => Some(SharpenMode::Off),
+            "auto" => Some(SharpenMode::Auto),
+            "on" | "true" | "yes" | "1" => Some(SharpenMode::On),
+            _ => None,
+        }
+    }
+}
+
+/// Settings for the unsharp mask applied after downscaling
+#[derive(Debug, Clone)]
+pub struct SharpenSettings {
+    pub mode: SharpenMode,
+    /// Strength; scales with the downscale ratio when unset
+    pub amount: Option<f32>,
+    /// Blur sigma in pixels
+    pub radius: f32,
+    /// Differences up to this (0-255) are left alone
+    pub threshold: u8,
+}
+
+impl Default for SharpenSettings {
+    fn default() -> Self {
+        Self {
+            mode: SharpenMode::Auto,
+            amount: None,
+            radius: SHARPEN_RADIUS,
+            threshold: SHARPEN_THRESHOLD,
+        }
+    }
+}
+
+impl SharpenSettings {
+    /// Strength for an image shrunk by `scale` (0-1): the requested amount, or
+    /// one that grows with every halving of the size
+    fn amount_for(&self, scale: f64) -> f32 {
+        self.amount.unwrap_or_else(|| {
+            (SHARPEN_STRENGTH_PER_HALVING * (1.0 / scale).log2()).min(SHARPEN_MAX_AUTO_AMOUNT)
+                as f32
+        })
+    }
+}
+
+/// Per-request settings for `optimize_image`
+#[derive(Debug, Clone)]
+pub struct OptimizationOptions {
+    /// WebP encoder quality (0-100)
+    pub quality: f32,
+    /// Images wider than this are scaled down, or the exact output width
+    /// for every resize mode but `Fit`
+    pub max_width: u32,
+    /// Images taller than this are scaled down, or the exact output height
+    /// for every resize mode but `Fit`
+    pub max_height: u32,
+    /// How images are brought to the box above
+    pub resize_mode: ResizeMode,
+    /// Let `Cover` keep the most detailed region instead of the center
+    pub smart_crop: bool,
+    /// RGBA fill for the borders added by `Pad`
+    pub pad_color: [u8; 4],
+    /// Container written for every input
+    pub format: OutputFormat,
+    /// Set when the client asked for `format` rather than getting the
+    /// default, so an original in another container is never handed back
+    pub format_required: bool,
+    /// Keep PNG inputs as PNG whatever `format` says
+    pub preserve_png: bool,
+    /// Filter used when scaling down
+    pub filter: FilterType,
+    /// Scale in linear light rather than on gamma-encoded values
+    pub linear_light: bool,
+    /// Unsharp mask applied after downscaling
+    pub sharpen: SharpenSettings,
+    /// Logo stamped onto the image before encoding
+    pub watermark: WatermarkSettings,
+    /// Text rendered onto the image before encoding
+    pub caption: CaptionSettings,
+    /// What happens to the input's metadata
+    pub metadata: MetadataPolicy,
+    /// Remove GPS data whatever the metadata policy says
+    pub strip_gps: bool,
+    /// What happens to embedded ICC profiles
+    pub color_profile: ColorProfilePolicy,
+    /// Return the original bytes when optimizing doesn't make the file smaller
+    pub never_larger: bool,
+    /// Widths of the WebP variants written for a `srcset`, none by default
+    pub variant_widths: Vec<u32>,
+    /// How repeated uploads within a batch are handled
+    pub duplicates: DuplicateSettings,
+    /// Settings for PNG output
+    pub png: PngSettings,
+    /// Settings for AVIF output
+    pub avif: AvifSettings,
+    /// Settings for JPEG output
+    pub jpeg: JpegSettings,
+    /// Byte budget for lossy output; quality and then dimensions are lowered to fit
+    pub target_size: Option<u64>,
+    /// Minimum SSIM (0-1) against the resized source; replaces `quality` with
+    /// the lowest setting that meets it
+    pub target_ssim: Option<f64>,
+    /// Keep only every n-th frame of animations
+    pub frame_step: usize,
+    /// Cap on the frame rate of animations
+    pub max_fps: Option<f32>,
+    /// Size SVG uploads are rendered at
+    pub svg: SvgSettings,
+    /// Largest images decoded at all; set by the server, never by clients
+    pub limits: DecodeLimits,
+}
+
+impl Default for OptimizationOptions {
+    fn default() -> Self {
+        Self {
+            quality: WEBP_QUALITY,
+            max_width: MAX_WIDTH,
+            max_height: MAX_HEIGHT,
+            resize_mode: ResizeMode::Fit,
+            smart_crop: false,
+            pad_color: [0, 0, 0, 0],
+            format: OutputFormat::WebP,
+            format_required: false,
+            preserve_png: false,
+            filter: FilterType::Triangle,
+            linear_light: false,
+            sharpen: SharpenSettings::default(),
+            watermark: WatermarkSettings::default(),
+            caption: CaptionSettings::default(),
+            metadata: MetadataPolicy::Strip,
+            strip_gps: true,
+            color_profile: ColorProfilePolicy::Srgb,
+            never_larger: true,
+            variant_widths: Vec::new(),
+            duplicates: DuplicateSettings::default(),
+            png: PngSettings::default(),
+            avif: AvifSettings::default(),
+            jpeg: JpegSettings::default(),
+            target_size: None,
+            target_ssim: None,
+            frame_step: 1,
+            max_fps: None,
+            svg: SvgSettings::default(),
+            limits: DecodeLimits::default(),
+        }
+    }
+}
+
+impl OptimizationOptions {
+    /// The format actually written for an input of the given format
+    pub fn output_format_for(&self, input: ImageFormat) -> OutputFormat {
+        if self.preserve_png && input == ImageFormat::Png {
+            OutputFormat::Png
+        } else {
+            self.format
+        }
+    }
+
+    /// Settle the settings that depend on the input: automatic sharpening
+    /// is only for photos
+    fn for_input(&self, input: ImageFormat) -> Self {
+        let mut options = self.clone();
+        if options.sharpen.mode == SharpenMode::Auto {
+            let graphic = matches!(
+                input,
+                ImageFormat::Png | ImageFormat::Gif | ImageFormat::Bmp | ImageFormat::Ico
+            );
+            options.sharpen.mode = if graphic {
+                SharpenMode::Off
+            } else {
+                SharpenMode::On
+            };
+        }
+        options
+    }
+
+    /// How images are resampled when they change size
+    fn resampler(&self) -> Resampler {
+        Resampler {
+            filter: self.filter,
+            linear: self.linear_light,
+        }
+    }
+
+    /// Whether an image of this size has to be resized
+    fn needs_resize(&self, width: u32, height: u32) -> bool {
+        match self.resize_mode {
+            ResizeMode::Fit => width > self.max_width || height > self.max_height,
+            _ => (width, height) != (self.max_width, self.max_height),
+        }
+    }
+
+    /// Reject an output box over the decode limits. Every mode but `Fit`
+    /// scales up to the box, so it's as large as the output can get.
+    fn check_output_box(&self) -> Result<()> {
+        if self.resize_mode == ResizeMode::Fit {
+            return Ok(());
+        }
+
+        self.limits
+            .check_dimensions(self.max_width, self.max_height)
+            .map_err(|e| anyhow::anyhow!("Requested output size is too large: {}", e))
+    }
+
+    /// Whether the embedded ICC profile is kept with the original pixels.
+    /// Stripping metadata drops the profile whatever `color_profile` says,
+    /// and whitelisting the profile keeps it.
+    pub fn keeps_icc_profile(&self) -> bool {
+        match &self.metadata {
+            MetadataPolicy::Strip => false,
+            MetadataPolicy::Keep => self.color_profile == ColorProfilePolicy::Keep,
+            MetadataPolicy::Whitelist(_) => self.metadata.allows(metadata::ICC_NAME),
+        }
+    }
+
+    /// Whether pixels are converted to sRGB for the given output format
+    pub fn converts_to_srgb(&self, output: OutputFormat) -> bool {
+        !self.keeps_icc_profile() || !metadata::can_embed(output)
+    }
+
+    /// Whether a watermark or caption will be drawn onto the image
+    pub fn has_overlays(&self) -> bool {
+        self.watermark.is_active() || self.caption.is_active()
+    }
+}
+
+/// What the optimizer settled on for a single image
+#[derive(Debug, Clone, Default)]
+pub struct OptimizationReport {
+    /// Width of the written image
+    pub width: u32,
+    /// Height of the written image
+    pub height: u32,
+    /// Encoder quality used, `None` for lossless output
+    pub quality: Option<f32>,
+    /// Whether the output fits `target_size`, `None` when no budget was requested
+    pub target_met: Option<bool>,
+    /// SSIM of the output against the resized source, when `target_ssim` was requested
+    pub ssim: Option<f64>,
+    /// Number of frames written, for animated output
+    pub frames: Option<usize>,
+    /// Names of the metadata fields and blocks that were removed
+    pub metadata_removed: Vec<String>,
+    /// Where the image was written. Has the input's extension when the
+    /// original was kept.
+    pub output_path: PathBuf,
+    /// Why the original bytes were returned instead of the optimized ones
+    pub kept_original: Option<String>,
+    /// Blurred previews for lazy loading
+    pub placeholder: Option<Placeholder>,
+    /// Dominant color and palette of the written image
+    pub palette: Option<Palette>,
+}
 
 /// Optimize an image based on its type
-pub async fn optimize_image<P: AsRef<Path>>(input_path: P, output_path: P) -> Result<()> {
+pub async fn optimize_image<P: AsRef<Path>>(
+    input_path: P,
+    output_path: P,
+    options: &OptimizationOptions,
+) -> Result<OptimizationReport> {
     let start = Instant::now();
     let input_path = input_path.as_ref();
     let output_path = output_path.as_ref();
@@ -27,23 +617,99 @@ pub async fn optimize_image<P: AsRef<Path>>(input_path: P, output_path: P) -> Re
         .await
         .with_context(|| format!("Failed to read image file: {:?}", input_path))?;
 
+    // SVGs are rendered up front and go through the pipeline as PNG
+    let (image_data, rasterized) = rasterize_svg(image_data, options).await?;
+
     // Detect image format
     let format = detect_image_format(&image_data)
         .with_context(|| format!("Failed to detect image format for: {:?}", input_path))?;
 
     info!("Detected format: {:?} for {:?}", format, input_path);
 
-    // Perform the optimization - simplified to just convert everything to WebP
-    // This provides good compression while being fast to process
-    let optimized_data = match format {
-        // For all formats, we'll just resize and convert to WebP for speed
-        _ => convert_to_webp(image_data.clone()).await?,
+    // Refuse images over the size limits before any pixels are decoded
+    let (width, height) = options.limits.check(&image_data)?;
+    debug!("Image header reports {}x{}", width, height);
+    options.check_output_box()?;
+
+    // Read the metadata before the pixels are re-encoded without it
+    let source_metadata = metadata::extract(&image_data, format);
+    let original_size = image_data.len() as u64;
+    let original_dimensions = upright_dimensions(&image_data);
+
+    // WebP gives good compression while being fast to process, AVIF trades
+    // encoding time for smaller photos; PNG output is kept lossless for
+    // consumers that can't read either
+    let output_format = options.output_format_for(format);
+    let options = &options.for_input(format);
+    let (optimized_data, mut report) = match output_format {
+        // Animated GIFs and WebPs keep every frame instead of collapsing to the first
+        OutputFormat::WebP if animation::is_animated(&image_data, format) => {
+            convert_animated(image_data, format, options.clone()).await?
+        }
+        OutputFormat::WebP => convert_lossy(image_data, LossyCodec::WebP, options.clone()).await?,
+        OutputFormat::Avif => convert_lossy(image_data, LossyCodec::Avif, options.clone()).await?,
+        OutputFormat::Jpeg => convert_lossy(image_data, LossyCodec::Jpeg, options.clone()).await?,
+        OutputFormat::Png => convert_to_png(image_data, format, options.clone()).await?,
     };
 
-    // Write the optimized image
-    tokio::fs::write(output_path, optimized_data)
-        .await
-        .with_context(|| format!("Failed to write optimized image to: {:?}", output_path))?;
+    // Write back the metadata the policy keeps, where the container allows it
+    let (mut kept_metadata, removed) =
+        metadata::apply_policy(source_metadata, &options.metadata, options.strip_gps);
+    report.metadata_removed = removed;
+    if options.color_profile == ColorProfilePolicy::Keep && !options.keeps_icc_profile() {
+        debug!("Converting to sRGB, the metadata policy drops the ICC profile");
+    }
+    if options.converts_to_srgb(output_format) && kept_metadata.icc.take().is_some() {
+        // The pixels are sRGB now, which is what viewers assume without a profile
+        report.metadata_removed.push(metadata::ICC_NAME.to_string());
+    }
+    if !metadata::can_embed(output_format) {
+        report
+            .metadata_removed
+            .extend(metadata::block_names(&kept_metadata));
+        kept_metadata = metadata::Metadata::default();
+    }
+    if !report.metadata_removed.is_empty() {
+        debug!("Removed metadata: {:?}", report.metadata_removed);
+    }
+    let optimized_data = metadata::embed(optimized_data, output_format, &kept_metadata)?;
+
+    report.output_path = output_path.to_path_buf();
+    let optimized_size = optimized_data.len() as u64;
+    // A rendered SVG has no raster original to fall back on
+    let keep_original = match rasterized {
+        true => None,
+        false => keep_original_reason(
+            options,
+            &report,
+            (format, output_format),
+            original_dimensions,
+            original_size,
+            optimized_size,
+        ),
+    };
+    if let Some(reason) = keep_original {
+        info!("Keeping the original of {:?}: {}", input_path, reason);
+
+        // The original keeps its own container, so it gets its own extension
+        let extension = format.extensions_str().first().copied().unwrap_or("img");
+        report.output_path = output_path.with_extension(extension);
+        report.quality = None;
+        report.target_met = options.target_size.map(|budget| original_size <= budget);
+        report.ssim = options.target_ssim.map(|_| 1.0);
+        report.kept_original = Some(reason);
+
+        tokio::fs::copy(input_path, &report.output_path)
+            .await
+            .with_context(|| {
+                format!("Failed to copy original image to: {:?}", report.output_path)
+            })?;
+    } else {
+        // Write the optimized image
+        tokio::fs::write(output_path, optimized_data)
+            .await
+            .with_context(|| format!("Failed to write optimized image to: {:?}", output_path))?;
+    }
 
     let duration = start.elapsed();
     info!(
@@ -53,7 +719,32 @@ pub async fn optimize_image<P: AsRef<Path>>(input_path: P, output_path: P) -> Re
         output_path
     );
 
-    Ok(())
+    Ok(report)
+}
+
+/// Render SVG input to PNG so the rest of the pipeline treats it like any
+/// raster upload. Returns the data to optimize and whether it was rendered.
+async fn rasterize_svg(data: Vec<u8>, options: &OptimizationOptions) -> Result<(Vec<u8>, bool)> {
+    if !svg::is_svg(&data) {
+        return Ok((data, false));
+    }
+
+    let settings = options.svg.clone();
+    let limits = options.limits.clone();
+    tokio::task::spawn_blocking(move || {
+        let img = svg::rasterize(&data, &settings, &limits)?;
+
+        // Only the pipeline reads this back, so fast compression will do
+        let mut png = Vec::new();
+        img.write_with_encoder(PngEncoder::new_with_quality(
+            Cursor::new(&mut png),
+            CompressionType::Fast,
+            PngFilterType::NoFilter,
+        ))?;
+        Ok((png, true))
+    })
+    .await
+    .with_context(|| "SVG rendering task failed")?
 }
 
 /// Detect the format of an image from its bytes
@@ -61,19 +752,943 @@ fn detect_image_format(data: &[u8]) -> Result<ImageFormat> {
     image::guess_format(data).with_context(|| "Failed to guess image format")
 }
 
-/// Convert an image to WebP format with fast settings
-async fn convert_to_webp(data: Vec<u8>) -> Result<Vec<u8>> {
+/// Dimensions of an image as displayed, after its EXIF orientation
+fn upright_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let (width, height) = image::io::Reader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()?;
+
+    // Orientations 5-8 swap the axes
+    match metadata::read_orientation(data) {
+        Some(5..=8) => Some((height, width)),
+        _ => Some((width, height)),
+    }
+}
+
+/// One width of a responsive image set
+#[derive(Debug, Clone)]
+pub struct ImageVariant {
+    pub width: u32,
+    pub height: u32,
+    /// Size of the written file in bytes
+    pub size: u64,
+    pub path: PathBuf,
+}
+
+/// Write WebP copies of an image at each of `options.variant_widths` for use
+/// in a `srcset`, named `{stem}-{width}w.webp`. Nothing is upscaled: widths
+/// past the image's own, after the size limits, collapse into a single
+/// full-width variant. Animations get no variants.
+pub async fn generate_variants<P: AsRef<Path>>(
+    input_path: P,
+    output_dir: P,
+    stem: &str,
+    options: &OptimizationOptions,
+) -> Result<Vec<ImageVariant>> {
+    let input_path = input_path.as_ref();
+    let output_dir = output_dir.as_ref();
+
+    let image_data = tokio::fs::read(input_path)
+        .await
+        .with_context(|| format!("Failed to read image file: {:?}", input_path))?;
+    let (image_data, _) = rasterize_svg(image_data, options).await?;
+
+    let format = detect_image_format(&image_data)?;
+    if animation::is_animated(&image_data, format) {
+        debug!("Skipping variants for animated image {:?}", input_path);
+        return Ok(Vec::new());
+    }
+
+    let options = options.for_input(format);
+    let encoded = tokio::task::spawn_blocking(move || -> Result<Vec<(u32, u32, Vec<u8>)>> {
+        let converter = srgb_converter(&image_data);
+        let img = load_upright(&image_data, converter.as_ref(), &options.limits)?;
+        let source_width = img.width();
+        let img = resize_to_options(img, &options)?;
+
+        let mut widths: Vec<u32> = options
+            .variant_widths
+            .iter()
+            .map(|width| (*width).min(img.width()))
+            .collect();
+        widths.sort_unstable();
+        widths.dedup();
+
+        let mut encoded = Vec::with_capacity(widths.len());
+        for width in widths {
+            let variant = resize_if_needed(img.clone(), width, img.height(), options.resampler());
+            let variant = sharpen_downscaled(variant, source_width, &options);
+            let variant = stamp_overlays(variant, &options);
+            let data = convert_to_webp_from_image(&variant, options.quality)?;
+            encoded.push((variant.width(), variant.height(), data));
+        }
+        Ok(encoded)
+    })
+    .await
+    .with_context(|| "Variant generation task failed")??;
+
+    let mut variants = Vec::with_capacity(encoded.len());
+    for (width, height, data) in encoded {
+        let path = output_dir.join(format!("{}-{}w.webp", stem, width));
+        tokio::fs::write(&path, &data)
+            .await
+            .with_context(|| format!("Failed to write variant to: {:?}", path))?;
+
+        variants.push(ImageVariant {
+            width,
+            height,
+            size: data.len() as u64,
+            path,
+        });
+    }
+
+    debug!("Wrote {} variants for {:?}", variants.len(), input_path);
+    Ok(variants)
+}
+
+/// Write a favicon and app icon set for an image into `output_dir`: a
+/// multi-size `favicon.ico` and the PNG icons in `icons::PNG_ICONS`, each
+/// made square as `settings.fit` says
+pub async fn generate_icons<P: AsRef<Path>>(
+    input_path: P,
+    output_dir: P,
+    settings: &IconSettings,
+    options: &OptimizationOptions,
+) -> Result<Vec<ImageVariant>> {
+    let input_path = input_path.as_ref();
+    let output_dir = output_dir.as_ref();
+
+    let image_data = tokio::fs::read(input_path)
+        .await
+        .with_context(|| format!("Failed to read image file: {:?}", input_path))?;
+
+    // Vectors are rendered with their shorter side at the largest icon size,
+    // unless the client asked for a size
+    let mut options = options.clone();
+    if svg::is_svg(&image_data) && options.svg.width.is_none() && options.svg.dpi.is_none() {
+        let (width, height) = svg::intrinsic_size(&image_data)?;
+        let largest = icons::PNG_ICONS
+            .iter()
+            .map(|icon| icon.size)
+            .max()
+            .unwrap_or(0);
+        options.svg.width = Some((width * largest as f32 / width.min(height)).ceil() as u32);
+    }
+    let (image_data, _) = rasterize_svg(image_data, &options).await?;
+
+    let format = detect_image_format(&image_data)?;
+    let options = options.for_input(format);
+    let settings = settings.clone();
+    let encoded = tokio::task::spawn_blocking(move || -> Result<Vec<(String, u32, Vec<u8>)>> {
+        let converter = srgb_converter(&image_data);
+        let img = load_upright(&image_data, converter.as_ref(), &options.limits)?;
+        if img.width().min(img.height()) < 512 {
+            warn!(
+                "Icon source is only {}x{}, the larger icons will be upscaled",
+                img.width(),
+                img.height()
+            );
+        }
+
+        let square = |size: u32| {
+            let icon = match settings.fit {
+                IconFit::Pad => pad(img.clone(), size, size, options.resampler(), [0, 0, 0, 0]),
+                IconFit::Cover => cover(
+                    img.clone(),
+                    size,
+                    size,
+                    options.resampler(),
+                    options.smart_crop,
+                ),
+            };
+            sharpen_downscaled(icon, img.width().max(img.height()), &options)
+        };
+
+        let mut encoded = Vec::with_capacity(icons::PNG_ICONS.len() + 1);
+        for spec in &icons::PNG_ICONS {
+            let icon = match spec.style {
+                IconStyle::Transparent => square(spec.size),
+                IconStyle::Opaque => flatten(&square(spec.size), spec.size, settings.background),
+                IconStyle::Maskable => {
+                    let inner = (spec.size as f64 * icons::MASKABLE_SAFE_ZONE).round() as u32;
+                    flatten(&square(inner), spec.size, settings.background)
+                }
+            };
+
+            let mut png = Vec::new();
+            icon.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+            encoded.push((
+                spec.filename.to_string(),
+                spec.size,
+                optimize_png(&png, &options.png)?,
+            ));
+        }
+
+        // Every ICO entry is a PNG, which all current browsers read
+        let frames = icons::ICO_SIZES
+            .iter()
+            .map(|size| {
+                let icon = square(*size).to_rgba8();
+                IcoFrame::as_png(icon.as_raw(), *size, *size, image::ColorType::Rgba8)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut ico = Vec::new();
+        IcoEncoder::new(&mut ico).encode_images(&frames)?;
+        let largest = icons::ICO_SIZES.iter().copied().max().unwrap_or(0);
+        encoded.push((icons::ICO_FILENAME.to_string(), largest, ico));
+
+        Ok(encoded)
+    })
+    .await
+    .with_context(|| "Icon generation task failed")??;
+
+    let mut written = Vec::with_capacity(encoded.len());
+    for (filename, size, data) in encoded {
+        let path = output_dir.join(&filename);
+        tokio::fs::write(&path, &data)
+            .await
+            .with_context(|| format!("Failed to write icon to: {:?}", path))?;
+
+        written.push(ImageVariant {
+            width: size,
+            height: size,
+            size: data.len() as u64,
+            path,
+        });
+    }
+
+    debug!("Wrote {} icons for {:?}", written.len(), input_path);
+    Ok(written)
+}
+
+/// Center an image on an opaque square of `size` filled with `background`
+fn flatten(img: &image::DynamicImage, size: u32, background: [u8; 4]) -> image::DynamicImage {
+    let mut canvas = RgbaImage::from_pixel(size, size, Rgba(background));
+    image::imageops::overlay(
+        &mut canvas,
+        &img.to_rgba8(),
+        (size.saturating_sub(img.width()) / 2) as i64,
+        (size.saturating_sub(img.height()) / 2) as i64,
+    );
+    image::DynamicImage::ImageRgba8(canvas)
+}
+
+/// Decide whether the original should be returned instead of the optimized
+/// output. It only stands in when the output saved nothing, the original is
+/// in a container the client accepts, the image wasn't resized to fit the
+/// limits, no metadata had to be removed from it and no watermark or caption
+/// was added to it.
+fn keep_original_reason(
+    options: &OptimizationOptions,
+    report: &OptimizationReport,
+    (input, output): (ImageFormat, OutputFormat),
+    original_dimensions: Option<(u32, u32)>,
+    original_size: u64,
+    optimized_size: u64,
+) -> Option<String> {
+    if !options.never_larger || optimized_size < original_size {
+        return None;
+    }
+
+    if options.format_required && !output.matches(input) {
+        debug!(
+            "Output is larger than the original, but the client asked for {:?}",
+            output
+        );
+        return None;
+    }
+
+    if original_dimensions != Some((report.width, report.height)) {
+        debug!("Output is larger than the original, but the original exceeds the size limits");
+        return None;
+    }
+
+    if !report.metadata_removed.is_empty() {
+        debug!("Output is larger than the original, but the original carries stripped metadata");
+        return None;
+    }
+
+    if options.has_overlays() {
+        debug!("Output is larger than the original, but the original has no watermark or caption");
+        return None;
+    }
+
+    Some(format!(
+        "Optimized output was {} bytes, no smaller than the {} byte original",
+        optimized_size, original_size
+    ))
+}
+
+/// Lossy encoders that the quality searches can drive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LossyCodec {
+    WebP,
+    Avif,
+    Jpeg,
+}
+
+impl LossyCodec {
+    /// The container this codec writes
+    fn output_format(&self) -> OutputFormat {
+        match self {
+            LossyCodec::WebP => OutputFormat::WebP,
+            LossyCodec::Avif => OutputFormat::Avif,
+            LossyCodec::Jpeg => OutputFormat::Jpeg,
+        }
+    }
+
+    /// The quality used when no search overrides it
+    fn base_quality(&self, options: &OptimizationOptions) -> f32 {
+        match self {
+            LossyCodec::WebP => options.quality,
+            LossyCodec::Avif => options.avif.quality,
+            LossyCodec::Jpeg => options.jpeg.quality,
+        }
+    }
+
+    /// Encode an image at the given quality
+    fn encode(
+        &self,
+        img: &image::DynamicImage,
+        quality: f32,
+        options: &OptimizationOptions,
+    ) -> Result<Vec<u8>> {
+        match self {
+            LossyCodec::WebP => convert_to_webp_from_image(img, quality),
+            LossyCodec::Avif => convert_to_avif_from_image(img, quality, options.avif.speed),
+            LossyCodec::Jpeg => convert_to_jpeg_from_image(img, quality, &options.jpeg),
+        }
+    }
+
+    /// Whether encoded output can be decoded again to measure SSIM
+    fn can_measure_ssim(&self) -> bool {
+        match self {
+            LossyCodec::WebP | LossyCodec::Jpeg => true,
+            // There's no pure-Rust AVIF decoder to compare against
+            LossyCodec::Avif => false,
+        }
+    }
+
+    /// Decode encoded bytes and compare them with the image they were encoded from
+    fn measure_ssim(&self, img: &image::DynamicImage, encoded: &[u8]) -> Result<f64> {
+        let decoded = match self {
+            LossyCodec::WebP => Decoder::new(encoded)
+                .decode()
+                .with_context(|| "Failed to decode WebP output")?
+                .to_image(),
+            LossyCodec::Avif => anyhow::bail!("SSIM can't be measured for AVIF output"),
+            LossyCodec::Jpeg => image::load_from_memory_with_format(encoded, ImageFormat::Jpeg)
+                .with_context(|| "Failed to decode JPEG output")?,
+        };
+
+        Ok(ssim::ssim(img, &decoded))
+    }
+}
+
+/// Decode, resize and encode an image with a lossy codec, honoring the
+/// perceptual and size targets in the options
+async fn convert_lossy(
+    data: Vec<u8>,
+    codec: LossyCodec,
+    options: OptimizationOptions,
+) -> Result<(Vec<u8>, OptimizationReport)> {
+    tokio::task::spawn_blocking(move || {
+        let converter = if options.converts_to_srgb(codec.output_format()) {
+            srgb_converter(&data)
+        } else {
+            None
+        };
+        let upright = load_upright(&data, converter.as_ref(), &options.limits)?;
+
+        // A byte budget may need smaller versions, which are made from the
+        // source rather than from the already resized image
+        let source = options.target_size.map(|_| upright.clone());
+        let img = prepare_lossy(upright, codec, &options)?;
+        let placeholder = placeholder::generate(&img);
+        let palette = palette::extract(&img);
+
+        let mut options = options;
+        if !codec.can_measure_ssim() && options.target_ssim.take().is_some() {
+            warn!("Ignoring SSIM target, it isn't supported for {:?}", codec);
+        }
+
+        // A perceptual target picks the quality instead of the fixed setting
+        let quality = match options.target_ssim {
+            Some(threshold) => search_ssim_quality(&img, codec, threshold, &options)?,
+            None => codec.base_quality(&options),
+        };
+
+        let (encoded, mut report) = match options.target_size.zip(source) {
+            Some((budget, source)) => {
+                encode_to_budget(img, &source, codec, quality, &options, budget)?
+            }
+            None => {
+                let encoded = codec.encode(&img, quality, &options)?;
+                let ssim = match options.target_ssim {
+                    Some(_) => Some(codec.measure_ssim(&img, &encoded)?),
+                    None => None,
+                };
+
+                let report = OptimizationReport {
+                    width: img.width(),
+                    height: img.height(),
+                    quality: Some(quality),
+                    target_met: None,
+                    ssim,
+                    ..Default::default()
+                };
+                (encoded, report)
+            }
+        };
+
+        report.placeholder = placeholder;
+        report.palette = palette;
+        Ok((encoded, report))
+    })
+    .await
+    .with_context(|| format!("{:?} conversion task failed", codec))?
+}
+
+/// Resize, sharpen and stamp the upright source the way the options ask,
+/// ready for a lossy encoder
+fn prepare_lossy(
+    source: image::DynamicImage,
+    codec: LossyCodec,
+    options: &OptimizationOptions,
+) -> Result<image::DynamicImage> {
+    let source_width = source.width();
+    let img = resize_to_options(source, options)?;
+    let img = sharpen_downscaled(img, source_width, options);
+    let img = stamp_overlays(img, options);
+
+    // JPEG has no alpha channel, so transparency is filled in first
+    Ok(match codec {
+        LossyCodec::Jpeg => fill_transparency(img, options.jpeg.background),
+        _ => img,
+    })
+}
+
+/// Encode within a byte budget, lowering quality first. With `fit`, the
+/// dimensions are lowered next, each smaller size made from `source`, until
+/// the output fits, the image can't shrink any further or the search runs
+/// out of encodes. The other modes promise an exact box, so a budget they
+/// can't meet at that size is reported as missed.
+fn encode_to_budget(
+    mut img: image::DynamicImage,
+    source: &image::DynamicImage,
+    codec: LossyCodec,
+    max_quality: f32,
+    options: &OptimizationOptions,
+    budget: u64,
+) -> Result<(Vec<u8>, OptimizationReport)> {
+    let (full_width, full_height) = (img.width(), img.height());
+    let exact_box = options.resize_mode != ResizeMode::Fit;
+    let mut encodes_left = MAX_TARGET_ENCODES;
+    let mut scale = 1.0;
+
+    loop {
+        let (quality, encoded, fits) =
+            search_budget_quality(&img, codec, max_quality, budget, options, &mut encodes_left)?;
+
+        // Another size costs at least the two encodes that bound its quality
+        let at_min_size = img.width().max(img.height()) <= MIN_TARGET_DIMENSION;
+        if fits || at_min_size || exact_box || encodes_left < 2 {
+            if !fits {
+                warn!(
+                    "Could not fit {}x{} image into {} bytes, smallest output is {} bytes",
+                    img.width(),
+                    img.height(),
+                    budget,
+                    encoded.len()
+                );
+            }
+
+            debug!(
+                "Settled on quality {:.1} at {}x{} for a {} byte budget",
+                quality,
+                img.width(),
+                img.height(),
+                budget
+            );
+
+            let ssim = match options.target_ssim {
+                Some(_) => Some(codec.measure_ssim(&img, &encoded)?),
+                None => None,
+            };
+
+            let report = OptimizationReport {
+                width: img.width(),
+                height: img.height(),
+                quality: Some(quality),
+                target_met: Some(fits),
+                ssim,
+                ..Default::default()
+            };
+            return Ok((encoded, report));
+        }
+
+        // The size grows with the pixel count, so the shortfall says roughly
+        // how far to shrink
+        let shrink = (budget as f64 / encoded.len() as f64)
+            .sqrt()
+            .min(TARGET_SCALE_STEP);
+        let smallest_scale = MIN_TARGET_DIMENSION as f64 / full_width.max(full_height) as f64;
+        scale = (scale * shrink).max(smallest_scale);
+        let smaller = OptimizationOptions {
+            max_width: ((full_width as f64 * scale).round() as u32).max(1),
+            max_height: ((full_height as f64 * scale).round() as u32).max(1),
+            ..options.clone()
+        };
+        img = prepare_lossy(source.clone(), codec, &smaller)?;
+    }
+}
+
+/// Find the highest quality whose output fits the budget. Returns the
+/// quality, the encoded bytes and whether they fit; when nothing fits the
+/// result at the quality floor is returned. Bisection stops early once
+/// `encodes_left` runs out, though the two bounds are always encoded.
+fn search_budget_quality(
+    img: &image::DynamicImage,
+    codec: LossyCodec,
+    max_quality: f32,
+    budget: u64,
+    options: &OptimizationOptions,
+    encodes_left: &mut usize,
+) -> Result<(f32, Vec<u8>, bool)> {
+    let fits = |data: &[u8]| data.len() as u64 <= budget;
+
+    *encodes_left = encodes_left.saturating_sub(1);
+    let encoded = codec.encode(img, max_quality, options)?;
+    if fits(&encoded) {
+        return Ok((max_quality, encoded, true));
+    }
+
+    let min_quality = MIN_TARGET_QUALITY.min(max_quality);
+    *encodes_left = encodes_left.saturating_sub(1);
+    let encoded = codec.encode(img, min_quality, options)?;
+    if !fits(&encoded) {
+        return Ok((min_quality, encoded, false));
+    }
+
+    // Bisect between a quality known to fit and one known not to
+    let steps = TARGET_QUALITY_STEPS.min(*encodes_left);
+    *encodes_left -= steps;
+    let mut best = (min_quality, encoded);
+    let mut high = max_quality;
+    for _ in 0..steps {
+        let quality = (best.0 + high) / 2.0;
+        let encoded = codec.encode(img, quality, options)?;
+        if fits(&encoded) {
+            best = (quality, encoded);
+        } else {
+            high = quality;
+        }
+    }
+
+    Ok((best.0, best.1, true))
+}
+
+/// Find the lowest quality whose output still reaches the SSIM threshold.
+/// Falls back to the highest quality when the threshold can't be met.
+fn search_ssim_quality(
+    img: &image::DynamicImage,
+    codec: LossyCodec,
+    threshold: f64,
+    options: &OptimizationOptions,
+) -> Result<f32> {
+    let meets = |quality: f32| -> Result<bool> {
+        let encoded = codec.encode(img, quality, options)?;
+        Ok(codec.measure_ssim(img, &encoded)? >= threshold)
+    };
+
+    if meets(MIN_SSIM_QUALITY)? {
+        return Ok(MIN_SSIM_QUALITY);
+    }
+
+    if !meets(MAX_SSIM_QUALITY)? {
+        warn!(
+            "SSIM {:.4} is out of reach, using quality {}",
+            threshold, MAX_SSIM_QUALITY
+        );
+        return Ok(MAX_SSIM_QUALITY);
+    }
+
+    // Bisect between a quality known to miss and one known to meet the threshold
+    let mut low = MIN_SSIM_QUALITY;
+    let mut high = MAX_SSIM_QUALITY;
+    for _ in 0..SSIM_QUALITY_STEPS {
+        let quality = ((low + high) / 2.0).round();
+        if quality <= low || quality >= high {
+            break;
+        }
+
+        if meets(quality)? {
+            high = quality;
+        } else {
+            low = quality;
+        }
+    }
+
+    debug!("Lowest quality reaching SSIM {:.4} is {}", threshold, high);
+    Ok(high)
+}
+
+/// Re-encode an animated GIF or WebP as an animated WebP, frame by frame
+async fn convert_animated(
+    data: Vec<u8>,
+    format: ImageFormat,
+    options: OptimizationOptions,
+) -> Result<(Vec<u8>, OptimizationReport)> {
     tokio::task::spawn_blocking(move || {
-        let img = image::load_from_memory(&data)?;
+        let mut animation = animation::decode(&data, format, options.limits.max_alloc)?;
+        animation.reduce_frames(options.frame_step, options.max_fps);
+
+        // Every frame is held at the output size until the encoder runs
+        if options.resize_mode != ResizeMode::Fit {
+            let frame_bytes = options.max_width as u64 * options.max_height as u64 * 4;
+            let total = frame_bytes * animation.frames.len() as u64;
+            if total > options.limits.max_alloc {
+                anyhow::bail!(
+                    "{} frames at {}x{} would take more than the {} byte limit",
+                    animation.frames.len(),
+                    options.max_width,
+                    options.max_height,
+                    options.limits.max_alloc
+                );
+            }
+        }
+
+        if options.target_size.is_some() || options.target_ssim.is_some() {
+            warn!("Ignoring size and SSIM targets, they aren't supported for animations");
+        }
+
+        let converter = if options.converts_to_srgb(OutputFormat::WebP) {
+            srgb_converter(&data)
+        } else {
+            None
+        };
 
-        // Resize if necessary
-        let img = resize_if_needed(img, MAX_WIDTH, MAX_HEIGHT);
+        // Smart crop could pick a different window for every frame, so
+        // animations are always cropped around the center
+        let options = OptimizationOptions {
+            smart_crop: false,
+            ..options
+        };
 
-        // Convert to WebP with our quality settings
-        convert_to_webp_from_image(&img)
+        // Every frame is composited to the full canvas, so they all resize alike
+        for frame in &mut animation.frames {
+            let img = image::DynamicImage::ImageRgba8(std::mem::take(&mut frame.image));
+            let img = match &converter {
+                Some(converter) => converter.convert(img),
+                None => img,
+            };
+            let source_width = img.width();
+            let img = resize_to_options(img, &options)?;
+            let img = sharpen_downscaled(img, source_width, &options);
+            frame.image = stamp_overlays(img, &options).into_rgba8();
+        }
+
+        let (width, height) = animation
+            .frames
+            .first()
+            .map(|frame| frame.image.dimensions())
+            .unwrap_or_default();
+        let first_frame = animation
+            .frames
+            .first()
+            .map(|frame| image::DynamicImage::ImageRgba8(frame.image.clone()));
+        let placeholder = first_frame.as_ref().and_then(placeholder::generate);
+        let palette = first_frame.as_ref().and_then(palette::extract);
+
+        let encoded = animation.encode_webp(options.quality)?;
+
+        debug!(
+            "Encoded {} frames at {}x{}, looping {} times",
+            animation.frames.len(),
+            width,
+            height,
+            animation.loop_count
+        );
+
+        let report = OptimizationReport {
+            width,
+            height,
+            quality: Some(options.quality),
+            target_met: None,
+            ssim: None,
+            frames: Some(animation.frames.len()),
+            placeholder,
+            palette,
+            ..Default::default()
+        };
+        Ok((encoded, report))
     })
     .await
-    .with_context(|| "WebP conversion task failed")?
+    .with_context(|| "Animation conversion task failed")?
+}
+
+/// Convert an image to an oxipng-optimized PNG
+async fn convert_to_png(
+    data: Vec<u8>,
+    format: ImageFormat,
+    options: OptimizationOptions,
+) -> Result<(Vec<u8>, OptimizationReport)> {
+    tokio::task::spawn_blocking(move || {
+        let (width, height) = options.limits.check(&data)?;
+
+        // PNGs that already fit and need no rotation are handed to oxipng
+        // untouched so the pixels stay bit-exact
+        let fits = !options.needs_resize(width, height);
+        let upright = metadata::read_orientation(&data).unwrap_or(1) == 1;
+        let converter = if options.converts_to_srgb(OutputFormat::Png) {
+            srgb_converter(&data)
+        } else {
+            None
+        };
+        let passthrough = format == ImageFormat::Png
+            && fits
+            && upright
+            && converter.is_none()
+            && !options.has_overlays()
+            && !options.png.quantize;
+        let mut quantized_ssim = None;
+        let (png_data, img) = if passthrough {
+            let img = options.limits.decode(&data)?;
+            (data, img)
+        } else {
+            let img = load_upright(&data, converter.as_ref(), &options.limits)?;
+            let source_width = img.width();
+            let img = resize_to_options(img, &options)?;
+            let img = sharpen_downscaled(img, source_width, &options);
+            let img = stamp_overlays(img, &options);
+
+            // oxipng writes the quantized image as an indexed PNG
+            let img = if options.png.quantize {
+                let quantized = quantize::quantize(&img, options.png.colors, options.png.dither);
+                quantized_ssim = options.target_ssim.map(|_| ssim::ssim(&img, &quantized));
+                quantized
+            } else {
+                img
+            };
+
+            let mut encoded = Vec::new();
+            img.write_to(&mut Cursor::new(&mut encoded), ImageFormat::Png)?;
+            (encoded, img)
+        };
+
+        let optimized = optimize_png(&png_data, &options.png)?;
+
+        // PNG output has no quality setting to trade for size, so the budget
+        // and SSIM targets are only reported
+        let report = OptimizationReport {
+            width: img.width(),
+            height: img.height(),
+            quality: None,
+            target_met: options
+                .target_size
+                .map(|budget| optimized.len() as u64 <= budget),
+            ssim: quantized_ssim.or(options.target_ssim.map(|_| 1.0)),
+            placeholder: placeholder::generate(&img),
+            palette: palette::extract(&img),
+            ..Default::default()
+        };
+        Ok((optimized, report))
+    })
+    .await
+    .with_context(|| "PNG optimization task failed")?
+}
+
+/// Run oxipng over PNG bytes with the given settings
+fn optimize_png(data: &[u8], settings: &PngSettings) -> Result<Vec<u8>> {
+    let mut options = PngOptions::from_preset(settings.level.min(PNG_MAX_LEVEL));
+
+    // Metadata chunks are written back afterwards according to the policy
+    options.strip = Headers::Keep(
+        metadata::PNG_RENDERING_CHUNKS
+            .iter()
+            .map(|chunk| chunk.to_string())
+            .collect(),
+    );
+
+    if settings.zopfli {
+        options.deflate = Deflaters::Zopfli {
+            iterations: NonZeroU8::new(ZOPFLI_ITERATIONS).unwrap(),
+        };
+    }
+
+    let optimized =
+        optimize_from_memory(data, &options).with_context(|| "oxipng failed to optimize PNG")?;
+
+    debug!(
+        "oxipng reduced PNG from {} to {} bytes",
+        data.len(),
+        optimized.len()
+    );
+
+    Ok(optimized)
+}
+
+/// Build a converter for the image's embedded ICC profile, `None` when it has
+/// no profile or is already sRGB
+fn srgb_converter(data: &[u8]) -> Option<SrgbConverter> {
+    let format = image::guess_format(data).ok()?;
+    let icc = metadata::read_icc_profile(data, format)?;
+    SrgbConverter::new(&icc)
+}
+
+/// Decode an image, convert it to sRGB when a converter is given and apply
+/// its EXIF orientation, so the dimension checks and everything after them
+/// see the upright image
+fn load_upright(
+    data: &[u8],
+    converter: Option<&SrgbConverter>,
+    limits: &DecodeLimits,
+) -> Result<image::DynamicImage> {
+    let img = limits.decode(data)?;
+    let img = match converter {
+        Some(converter) => converter.convert(img),
+        None => img,
+    };
+
+    Ok(match metadata::read_orientation(data) {
+        Some(orientation) => metadata::apply_orientation(img, orientation),
+        None => img,
+    })
+}
+
+/// Bring an image to the size and shape the options ask for
+fn resize_to_options(
+    img: image::DynamicImage,
+    options: &OptimizationOptions,
+) -> Result<image::DynamicImage> {
+    let (width, height) = (options.max_width.max(1), options.max_height.max(1));
+    if !options.needs_resize(img.width(), img.height()) {
+        return Ok(img);
+    }
+
+    options.check_output_box()?;
+    let resampler = options.resampler();
+    Ok(match options.resize_mode {
+        ResizeMode::Fit => resize_if_needed(img, width, height, resampler),
+        ResizeMode::Fill => {
+            debug!(
+                "Stretching image from {}x{} to {}x{}",
+                img.width(),
+                img.height(),
+                width,
+                height
+            );
+            resampler.resize_exact(&img, width, height)
+        }
+        ResizeMode::Cover => cover(img, width, height, resampler, options.smart_crop),
+        ResizeMode::Pad => pad(img, width, height, resampler, options.pad_color),
+    })
+}
+
+/// Draw the watermark and then the caption onto the resized image
+fn stamp_overlays(img: image::DynamicImage, options: &OptimizationOptions) -> image::DynamicImage {
+    let img = watermark::apply(img, &options.watermark);
+    caption::apply(img, &options.caption)
+}
+
+/// Run the unsharp mask over an image that was shrunk from `source_width`,
+/// when the options ask for it
+fn sharpen_downscaled(
+    img: image::DynamicImage,
+    source_width: u32,
+    options: &OptimizationOptions,
+) -> image::DynamicImage {
+    if options.sharpen.mode != SharpenMode::On || img.width() >= source_width {
+        return img;
+    }
+
+    let amount = options
+        .sharpen
+        .amount_for(img.width() as f64 / source_width as f64);
+    if amount <= 0.0 {
+        return img;
+    }
+
+    debug!(
+        "Sharpening with amount {:.2}, radius {:.2}, threshold {}",
+        amount, options.sharpen.radius, options.sharpen.threshold
+    );
+    sharpen::unsharp_mask(
+        &img,
+        amount,
+        options.sharpen.radius,
+        options.sharpen.threshold,
+    )
+}
+
+/// Scale an image to cover the box, then crop the overflow from the center
+/// or, with `smart`, around the most detailed region
+fn cover(
+    img: image::DynamicImage,
+    width: u32,
+    height: u32,
+    resampler: Resampler,
+    smart: bool,
+) -> image::DynamicImage {
+    let ratio = f64::max(
+        width as f64 / img.width() as f64,
+        height as f64 / img.height() as f64,
+    );
+    let scaled_width = ((img.width() as f64 * ratio).round() as u32).max(width);
+    let scaled_height = ((img.height() as f64 * ratio).round() as u32).max(height);
+
+    let scaled = if (scaled_width, scaled_height) == (img.width(), img.height()) {
+        img
+    } else {
+        resampler.resize_exact(&img, scaled_width, scaled_height)
+    };
+
+    let (x, y) = if smart {
+        crop::smart_crop_origin(&scaled, width, height)
+    } else {
+        ((scaled_width - width) / 2, (scaled_height - height) / 2)
+    };
+
+    debug!(
+        "Cropping {}x{} at {},{} from {}x{}",
+        width, height, x, y, scaled_width, scaled_height
+    );
+
+    scaled.crop_imm(x, y, width, height)
+}
+
+/// Scale an image to fit inside the box and center it on a canvas of the
+/// box size filled with `color`
+fn pad(
+    img: image::DynamicImage,
+    width: u32,
+    height: u32,
+    resampler: Resampler,
+    color: [u8; 4],
+) -> image::DynamicImage {
+    let ratio = f64::min(
+        width as f64 / img.width() as f64,
+        height as f64 / img.height() as f64,
+    );
+    let scaled_width = ((img.width() as f64 * ratio).round() as u32).clamp(1, width);
+    let scaled_height = ((img.height() as f64 * ratio).round() as u32).clamp(1, height);
+    let scaled = resampler.resize_exact(&img, scaled_width, scaled_height);
+
+    debug!(
+        "Padding {}x{} image to {}x{}",
+        scaled_width, scaled_height, width, height
+    );
+
+    let mut canvas = RgbaImage::from_pixel(width, height, Rgba(color));
+    image::imageops::overlay(
+        &mut canvas,
+        &scaled.to_rgba8(),
+        ((width - scaled_width) / 2) as i64,
+        ((height - scaled_height) / 2) as i64,
+    );
+    image::DynamicImage::ImageRgba8(canvas)
 }
 
 /// Resize an image if it exceeds the maximum dimensions
@@ -81,6 +1696,7 @@ fn resize_if_needed(
     img: image::DynamicImage,
     max_width: u32,
     max_height: u32,
+    resampler: Resampler,
 ) -> image::DynamicImage {
     let width = img.width();
     let height = img.height();
@@ -96,20 +1712,19 @@ fn resize_if_needed(
         max_height as f64 / height as f64,
     );
 
-    let new_width = (width as f64 * ratio).round() as u32;
-    let new_height = (height as f64 * ratio).round() as u32;
+    let new_width = ((width as f64 * ratio).round() as u32).max(1);
+    let new_height = ((height as f64 * ratio).round() as u32).max(1);
 
     debug!(
         "Resizing image from {}x{} to {}x{}",
         width, height, new_width, new_height
     );
 
-    // Use a faster resize algorithm for speed
-    img.resize(new_width, new_height, image::imageops::FilterType::Triangle)
+    resampler.resize_exact(&img, new_width, new_height)
 }
 
 /// Convert an image::DynamicImage to WebP format
-fn convert_to_webp_from_image(img: &image::DynamicImage) -> Result<Vec<u8>> {
+fn convert_to_webp_from_image(img: &image::DynamicImage, quality: f32) -> Result<Vec<u8>> {
     // Get RGBA data
     let rgba = img.to_rgba8();
 
@@ -117,8 +1732,80 @@ fn convert_to_webp_from_image(img: &image::DynamicImage) -> Result<Vec<u8>> {
     let encoder = Encoder::from_rgba(rgba.as_raw(), img.width(), img.height());
 
     // Set quality and encode
-    let encoded = encoder.encode(WEBP_QUALITY);
+    let encoded = encoder.encode(quality);
 
     // Convert to Vec<u8>
     Ok(encoded.to_vec())
 }
+
+/// Convert an image::DynamicImage to AVIF format
+fn convert_to_avif_from_image(
+    img: &image::DynamicImage,
+    quality: f32,
+    speed: u8,
+) -> Result<Vec<u8>> {
+    let rgba = img.to_rgba8();
+    let pixels: Vec<RGBA8> = rgba
+        .pixels()
+        .map(|p| RGBA8::new(p[0], p[1], p[2], p[3]))
+        .collect();
+
+    let encoded = ravif::Encoder::new()
+        .with_quality(quality)
+        .with_alpha_quality(quality)
+        .with_speed(speed.clamp(1, 10))
+        .encode_rgba(Img::new(
+            pixels.as_slice(),
+            img.width() as usize,
+            img.height() as usize,
+        ))
+        .with_context(|| "AVIF encoding failed")?;
+
+    Ok(encoded.avif_file)
+}
+
+/// Convert an image::DynamicImage to JPEG format. Grayscale images are
+/// written with a single channel.
+fn convert_to_jpeg_from_image(
+    img: &image::DynamicImage,
+    quality: f32,
+    settings: &JpegSettings,
+) -> Result<Vec<u8>> {
+    let width = u16::try_from(img.width()).with_context(|| "Image is too wide for JPEG")?;
+    let height = u16::try_from(img.height()).with_context(|| "Image is too tall for JPEG")?;
+
+    let mut encoded = Vec::new();
+    let mut encoder = JpegEncoder::new(&mut encoded, quality.round().clamp(1.0, 100.0) as u8);
+    encoder.set_progressive(settings.progressive);
+    encoder.set_sampling_factor(settings.subsampling.sampling_factor());
+    encoder.set_optimized_huffman_tables(settings.optimize_huffman);
+
+    let result = match img {
+        image::DynamicImage::ImageLuma8(gray) => {
+            encoder.encode(gray.as_raw(), width, height, JpegColorType::Luma)
+        }
+        _ => encoder.encode(img.to_rgb8().as_raw(), width, height, JpegColorType::Rgb),
+    };
+    result.with_context(|| "JPEG encoding failed")?;
+
+    Ok(encoded)
+}
+
+/// Blend transparent pixels onto an opaque background color
+fn fill_transparency(img: image::DynamicImage, background: [u8; 4]) -> image::DynamicImage {
+    if !img.color().has_alpha() {
+        return img;
+    }
+
+    let mut rgba = img.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+        let alpha = pixel[3] as u32;
+        for channel in 0..3 {
+            pixel[channel] =
+                ((pixel[channel] as u32 * alpha + background[channel] as u32 * (255 - alpha) + 127)
+                    / 255) as u8;
+        }
+        pixel[3] = 255;
+    }
+    image::DynamicImage::ImageRgb8(image::DynamicImage::ImageRgba8(rgba).to_rgb8())
+}
@@ -0,0 +1,137 @@
+use image::imageops::FilterType;
+use image::DynamicImage;
+
+// Colors are counted on a thumbnail - plenty for a palette and it keeps the
+// median cut cheap on large photos
+const SAMPLE_SIZE: u32 = 64;
+const PALETTE_SIZE: usize = 8;
+
+// Pixels more transparent than this don't count towards the palette
+const MIN_ALPHA: u8 = 128;
+
+/// The main colors of an image
+#[derive(Debug, Clone)]
+pub struct Palette {
+    /// The palette color closest to the most pixels
+    pub dominant: [u8; 3],
+    /// Up to `PALETTE_SIZE` colors, most common first
+    pub colors: Vec<[u8; 3]>,
+}
+
+impl Palette {
+    /// Format a color as `#rrggbb`
+    pub fn hex(color: [u8; 3]) -> String {
+        format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+    }
+}
+
+/// Find the dominant color and palette with a median cut over the opaque
+/// pixels. Fully transparent images have no palette.
+pub fn extract(img: &DynamicImage) -> Option<Palette> {
+    let sample = img
+        .resize(SAMPLE_SIZE, SAMPLE_SIZE, FilterType::Triangle)
+        .to_rgba8();
+    let pixels: Vec<[u8; 3]> = sample
+        .pixels()
+        .filter(|pixel| pixel.0[3] >= MIN_ALPHA)
+        .map(|pixel| [pixel.0[0], pixel.0[1], pixel.0[2]])
+        .collect();
+
+    if pixels.is_empty() {
+        return None;
+    }
+
+    let mut boxes = vec![pixels.clone()];
+    while boxes.len() < PALETTE_SIZE {
+        // Split the box spanning the widest channel range
+        let Some((index, channel)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, pixels)| pixels.len() > 1)
+            .map(|(index, pixels)| {
+                let (channel, range) = widest_channel(pixels);
+                (index, channel, range)
+            })
+            .filter(|(_, _, range)| *range > 0)
+            .max_by_key(|(_, _, range)| *range)
+            .map(|(index, channel, _)| (index, channel))
+        else {
+            break;
+        };
+
+        let mut pixels = boxes.swap_remove(index);
+        pixels.sort_unstable_by_key(|pixel| pixel[channel]);
+        let upper = pixels.split_off(pixels.len() / 2);
+        boxes.push(pixels);
+        boxes.push(upper);
+    }
+
+    let mut colors: Vec<[u8; 3]> = boxes.iter().map(|pixels| average(pixels)).collect();
+    colors.sort_unstable();
+    colors.dedup();
+
+    // Median cut splits boxes into equal halves, so rank the colors by how
+    // many pixels are closest to each instead of by box size
+    let mut counts = vec![0usize; colors.len()];
+    for pixel in &pixels {
+        counts[nearest(&colors, *pixel)] += 1;
+    }
+
+    let mut ranked: Vec<([u8; 3], usize)> = colors
+        .into_iter()
+        .zip(counts)
+        .filter(|(_, count)| *count > 0)
+        .collect();
+    ranked.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    let colors: Vec<[u8; 3]> = ranked.into_iter().map(|(color, _)| color).collect();
+
+    Some(Palette {
+        dominant: colors[0],
+        colors,
+    })
+}
+
+/// Index of the palette color closest to a pixel
+fn nearest(colors: &[[u8; 3]], pixel: [u8; 3]) -> usize {
+    colors
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, color)| {
+            (0..3)
+                .map(|channel| {
+                    let delta = color[channel] as i32 - pixel[channel] as i32;
+                    delta * delta
+                })
+                .sum::<i32>()
+        })
+        .map(|(index, _)| index)
+        .unwrap_or(0)
+}
+
+/// The channel with the largest spread in a box, and that spread
+fn widest_channel(pixels: &[[u8; 3]]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let min = pixels.iter().map(|pixel| pixel[channel]).min().unwrap_or(0);
+            let max = pixels.iter().map(|pixel| pixel[channel]).max().unwrap_or(0);
+            (channel, max - min)
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap_or((0, 0))
+}
+
+fn average(pixels: &[[u8; 3]]) -> [u8; 3] {
+    let mut sums = [0u64; 3];
+    for pixel in pixels {
+        for channel in 0..3 {
+            sums[channel] += pixel[channel] as u64;
+        }
+    }
+
+    let count = pixels.len().max(1) as u64;
+    [
+        (sums[0] / count) as u8,
+        (sums[1] / count) as u8,
+        (sums[2] / count) as u8,
+    ]
+}
@@ -0,0 +1,126 @@
+use anyhow::Result;
+use image::imageops::FilterType;
+use image::DynamicImage;
+
+use crate::limits::DecodeLimits;
+use crate::metadata;
+use crate::svg::{self, SvgSettings};
+
+// Hamming distances (out of 64 bits) at or below which two uploads count as
+// the same picture, or as near-identical shots such as a burst
+const DUPLICATE_THRESHOLD: u32 = 4;
+const NEAR_DUPLICATE_THRESHOLD: u32 = 12;
+
+/// What happens to uploads that look like an earlier one in the batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateMode {
+    /// Don't hash uploads at all
+    Off,
+    /// Optimize everything, but mark duplicates and near-duplicates
+    Flag,
+    /// Leave duplicates out of the batch; near-duplicates are still marked
+    Skip,
+}
+
+impl DuplicateMode {
+    /// Parse a mode as sent by the client
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "off" | "false" | "no" | "0" => Some(DuplicateMode::Off),
+            "flag" => Some(DuplicateMode::Flag),
+            "skip" => Some(DuplicateMode::Skip),
+            _ => None,
+        }
+    }
+}
+
+/// How alike two uploads are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Similarity {
+    Duplicate,
+    NearDuplicate,
+}
+
+impl Similarity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Similarity::Duplicate => "duplicate",
+            Similarity::NearDuplicate => "near_duplicate",
+        }
+    }
+}
+
+/// Settings for spotting repeated uploads within a batch
+#[derive(Debug, Clone)]
+pub struct DuplicateSettings {
+    pub mode: DuplicateMode,
+    /// Hash distances up to this are duplicates
+    pub threshold: u32,
+    /// Hash distances up to this are near-duplicates
+    pub near_threshold: u32,
+}
+
+impl Default for DuplicateSettings {
+    fn default() -> Self {
+        Self {
+            mode: DuplicateMode::Flag,
+            threshold: DUPLICATE_THRESHOLD,
+            near_threshold: NEAR_DUPLICATE_THRESHOLD,
+        }
+    }
+}
+
+impl DuplicateSettings {
+    /// Classify the distance between two hashes, `None` for different images
+    pub fn classify(&self, distance: u32) -> Option<Similarity> {
+        if distance <= self.threshold {
+            Some(Similarity::Duplicate)
+        } else if distance <= self.near_threshold {
+            Some(Similarity::NearDuplicate)
+        } else {
+            None
+        }
+    }
+}
+
+/// Difference hash of an encoded image, taken after the EXIF orientation is
+/// applied so a rotated copy matches its original. SVGs are hashed at their
+/// own size.
+pub fn hash_image_data(data: &[u8], limits: &DecodeLimits) -> Result<u64> {
+    if svg::is_svg(data) {
+        return Ok(dhash(&svg::rasterize(
+            data,
+            &SvgSettings::default(),
+            limits,
+        )?));
+    }
+
+    let img = limits.decode(data)?;
+    let img = match metadata::read_orientation(data) {
+        Some(orientation) => metadata::apply_orientation(img, orientation),
+        None => img,
+    };
+    Ok(dhash(&img))
+}
+
+/// 64-bit difference hash: each bit says whether a pixel of a 9x8 grayscale
+/// thumbnail is brighter than its right neighbour. Re-encoding, resizing and
+/// small edits flip only a few bits.
+pub fn dhash(img: &DynamicImage) -> u64 {
+    let thumbnail = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = thumbnail.get_pixel(x, y).0[0];
+            let right = thumbnail.get_pixel(x + 1, y).0[0];
+            hash = (hash << 1) | (left > right) as u64;
+        }
+    }
+    hash
+}
+
+/// Number of differing bits between two hashes
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
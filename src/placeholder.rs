@@ -0,0 +1,73 @@
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use image::imageops::FilterType;
+use image::DynamicImage;
+use tracing::warn;
+use webp::Encoder;
+
+// BlurHash only keeps a handful of frequencies, so a thumbnail this size
+// gives the same hash as the full image at a fraction of the cost
+const BLURHASH_SAMPLE_SIZE: u32 = 32;
+const BLURHASH_COMPONENTS: u32 = 4;
+
+// The inline preview is meant to be blurred by the browser, so it can be tiny
+// and heavily compressed
+const LQIP_WIDTH: u32 = 20;
+const LQIP_QUALITY: f32 = 20.0;
+
+/// Placeholders the frontend can show while the real image loads
+#[derive(Debug, Clone)]
+pub struct Placeholder {
+    /// BlurHash of the image
+    pub blurhash: String,
+    /// Tiny WebP of the image as a `data:` URI
+    pub lqip: String,
+}
+
+/// Build the placeholders for a decoded image. Failures are logged and only
+/// cost the placeholder, never the optimization.
+pub fn generate(img: &DynamicImage) -> Option<Placeholder> {
+    match try_generate(img) {
+        Ok(placeholder) => Some(placeholder),
+        Err(e) => {
+            warn!("Failed to generate placeholder: {}", e);
+            None
+        }
+    }
+}
+
+fn try_generate(img: &DynamicImage) -> Result<Placeholder> {
+    // More components along the longer side keep the hash's detail even
+    let (components_x, components_y) = if img.width() >= img.height() {
+        (BLURHASH_COMPONENTS, BLURHASH_COMPONENTS - 1)
+    } else {
+        (BLURHASH_COMPONENTS - 1, BLURHASH_COMPONENTS)
+    };
+
+    let sample = img
+        .resize(
+            BLURHASH_SAMPLE_SIZE,
+            BLURHASH_SAMPLE_SIZE,
+            FilterType::Triangle,
+        )
+        .to_rgba8();
+    let blurhash = blurhash::encode(
+        components_x,
+        components_y,
+        sample.width(),
+        sample.height(),
+        sample.as_raw(),
+    )
+    .map_err(|e| anyhow::anyhow!("BlurHash encoding failed: {}", e))?;
+
+    let width = LQIP_WIDTH.min(img.width());
+    let height = ((img.height() as f64 * width as f64 / img.width() as f64).round() as u32).max(1);
+    let tiny = img
+        .resize_exact(width, height, FilterType::Triangle)
+        .to_rgba8();
+    let webp = Encoder::from_rgba(tiny.as_raw(), tiny.width(), tiny.height()).encode(LQIP_QUALITY);
+    let lqip = format!("data:image/webp;base64,{}", STANDARD.encode(&*webp));
+
+    Ok(Placeholder { blurhash, lqip })
+}
@@ -0,0 +1,38 @@
+use color_quant::NeuQuant;
+use image::{DynamicImage, RgbaImage};
+use std::collections::HashSet;
+
+// NeuQuant learns from every n-th pixel: 1 is the most accurate, 30 the
+// fastest, and 10 is its own recommended trade-off
+const NEUQUANT_SAMPLE_FACTOR: i32 = 10;
+
+/// Reduce an image to at most `colors` RGBA colors so oxipng can write it as
+/// an indexed PNG. Images that already fit the palette are left untouched.
+/// With `dither`, Floyd-Steinberg error diffusion hides banding in gradients.
+pub fn quantize(img: &DynamicImage, colors: u16, dither: bool) -> DynamicImage {
+    let mut rgba = img.to_rgba8();
+    let colors = colors.clamp(2, 256) as usize;
+    if fits_palette(&rgba, colors) {
+        return DynamicImage::ImageRgba8(rgba);
+    }
+
+    let palette = NeuQuant::new(NEUQUANT_SAMPLE_FACTOR, colors, rgba.as_raw());
+    if dither {
+        image::imageops::dither(&mut rgba, &palette);
+    } else {
+        for pixel in rgba.pixels_mut() {
+            palette.map_pixel(&mut pixel.0);
+        }
+    }
+
+    DynamicImage::ImageRgba8(rgba)
+}
+
+/// Whether an image uses no more than `colors` distinct colors
+fn fits_palette(rgba: &RgbaImage, colors: usize) -> bool {
+    let mut seen = HashSet::new();
+    rgba.pixels().all(|pixel| {
+        seen.insert(pixel.0);
+        seen.len() <= colors
+    })
+}
@@ -0,0 +1,65 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, Rgba32FImage};
+
+use crate::utils;
+
+/// How pixels are interpolated when an image changes size
+#[derive(Debug, Clone, Copy)]
+pub struct Resampler {
+    pub filter: FilterType,
+    /// Resize in linear light instead of on the gamma-encoded sRGB values.
+    /// Averaging encoded values darkens thin lines and high-contrast edges.
+    pub linear: bool,
+}
+
+impl Resampler {
+    /// Resize to exactly `width`x`height`
+    pub fn resize_exact(&self, img: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+        if !self.linear {
+            return img.resize_exact(width, height, self.filter);
+        }
+
+        let high_depth = utils::is_high_depth(img);
+
+        let mut linear = img.to_rgba32f();
+        map_color_channels(&mut linear, srgb_to_linear);
+
+        let mut resized = image::imageops::resize(&linear, width, height, self.filter);
+        // Lanczos and Catmull-Rom overshoot, which the transfer curve can't take
+        map_color_channels(&mut resized, |value| linear_to_srgb(value.clamp(0.0, 1.0)));
+
+        let resized = DynamicImage::ImageRgba32F(resized);
+        if high_depth {
+            DynamicImage::ImageRgba16(resized.to_rgba16())
+        } else {
+            DynamicImage::ImageRgba8(resized.to_rgba8())
+        }
+    }
+}
+
+/// Apply a function to the color channels, leaving alpha alone
+fn map_color_channels(img: &mut Rgba32FImage, f: impl Fn(f32) -> f32) {
+    for pixel in img.pixels_mut() {
+        for channel in &mut pixel.0[..3] {
+            *channel = f(*channel);
+        }
+    }
+}
+
+/// The sRGB transfer function, from encoded to linear values
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// The inverse sRGB transfer function, from linear to encoded values
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
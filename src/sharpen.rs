@@ -0,0 +1,31 @@
+use image::DynamicImage;
+
+use crate::utils;
+
+/// Sharpen an image by adding back `amount` times the difference between it
+/// and a Gaussian blur of `radius` (the blur's sigma, in pixels). Differences
+/// of `threshold` or less on the 0-255 scale are left alone so flat areas and
+/// noise don't get amplified. Alpha is untouched.
+pub fn unsharp_mask(img: &DynamicImage, amount: f32, radius: f32, threshold: u8) -> DynamicImage {
+    let high_depth = utils::is_high_depth(img);
+
+    let mut sharpened = img.to_rgba32f();
+    let blurred = image::imageops::blur(&sharpened, radius);
+    let threshold = threshold as f32 / 255.0;
+
+    for (pixel, blurred) in sharpened.pixels_mut().zip(blurred.pixels()) {
+        for channel in 0..3 {
+            let difference = pixel.0[channel] - blurred.0[channel];
+            if difference.abs() > threshold {
+                pixel.0[channel] = (pixel.0[channel] + amount * difference).clamp(0.0, 1.0);
+            }
+        }
+    }
+
+    let sharpened = DynamicImage::ImageRgba32F(sharpened);
+    if high_depth {
+        DynamicImage::ImageRgba16(sharpened.to_rgba16())
+    } else {
+        DynamicImage::ImageRgba8(sharpened.to_rgba8())
+    }
+}
@@ -0,0 +1,84 @@
+use image::{DynamicImage, GrayImage};
+
+// Window size and step for the local statistics - overlapping 8x8 windows
+// are the usual trade-off between accuracy and speed
+const WINDOW_SIZE: u32 = 8;
+const WINDOW_STEP: u32 = 4;
+
+// Stabilizing constants from the original SSIM paper, for 8-bit samples
+const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+/// Mean structural similarity between the luma of two images of the same
+/// size, where 1.0 means identical. Images of different sizes score 0.0.
+pub fn ssim(reference: &DynamicImage, candidate: &DynamicImage) -> f64 {
+    if reference.width() != candidate.width() || reference.height() != candidate.height() {
+        return 0.0;
+    }
+
+    let reference = reference.to_luma8();
+    let candidate = candidate.to_luma8();
+
+    let (width, height) = reference.dimensions();
+    let window_width = WINDOW_SIZE.min(width);
+    let window_height = WINDOW_SIZE.min(height);
+
+    let mut total = 0.0;
+    let mut windows = 0u32;
+
+    let mut y = 0;
+    while y + window_height <= height {
+        let mut x = 0;
+        while x + window_width <= width {
+            total += window_ssim(&reference, &candidate, x, y, window_width, window_height);
+            windows += 1;
+            x += WINDOW_STEP;
+        }
+        y += WINDOW_STEP;
+    }
+
+    if windows == 0 {
+        return 1.0;
+    }
+
+    total / windows as f64
+}
+
+/// SSIM of a single window
+fn window_ssim(
+    reference: &GrayImage,
+    candidate: &GrayImage,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+) -> f64 {
+    let count = (width * height) as f64;
+
+    let mut sum_a = 0.0;
+    let mut sum_b = 0.0;
+    let mut sum_aa = 0.0;
+    let mut sum_bb = 0.0;
+    let mut sum_ab = 0.0;
+
+    for dy in 0..height {
+        for dx in 0..width {
+            let a = reference.get_pixel(x + dx, y + dy).0[0] as f64;
+            let b = candidate.get_pixel(x + dx, y + dy).0[0] as f64;
+            sum_a += a;
+            sum_b += b;
+            sum_aa += a * a;
+            sum_bb += b * b;
+            sum_ab += a * b;
+        }
+    }
+
+    let mean_a = sum_a / count;
+    let mean_b = sum_b / count;
+    let var_a = sum_aa / count - mean_a * mean_a;
+    let var_b = sum_bb / count - mean_b * mean_b;
+    let covariance = sum_ab / count - mean_a * mean_b;
+
+    ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+        / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2))
+}
@@ -0,0 +1,119 @@
+use anyhow::{Context, Result};
+use image::{DynamicImage, RgbaImage};
+use resvg::tiny_skia::{Pixmap, Transform};
+use resvg::usvg::{self, fontdb, ImageHrefResolver};
+use std::sync::{Arc, OnceLock};
+use tracing::debug;
+
+use crate::caption::FONT_DATA;
+use crate::limits::DecodeLimits;
+
+// SVG user units are CSS pixels, which are defined at 96 DPI
+const SVG_BASE_DPI: f32 = 96.0;
+
+/// Size SVG uploads are rendered at
+#[derive(Debug, Clone, Default)]
+pub struct SvgSettings {
+    /// Render width in pixels, the height follows the aspect ratio
+    pub width: Option<u32>,
+    /// Render resolution, where 96 is the SVG's own size. Ignored when a
+    /// width is set.
+    pub dpi: Option<f32>,
+}
+
+/// Check whether data looks like an SVG document. `image` can't detect
+/// these, so this sniffs the start of the text for an `<svg` element.
+pub fn is_svg(data: &[u8]) -> bool {
+    let head = &data[..data.len().min(4096)];
+    let head = head.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(head);
+    let text = String::from_utf8_lossy(head);
+    let text = text.trim_start();
+    (text.starts_with("<?xml") || text.starts_with("<svg") || text.starts_with("<!"))
+        && text.contains("<svg")
+}
+
+/// Render an SVG document at the requested size. The output size is checked
+/// against the decode limits before the pixmap is allocated.
+pub fn rasterize(
+    data: &[u8],
+    settings: &SvgSettings,
+    limits: &DecodeLimits,
+) -> Result<DynamicImage> {
+    let tree = parse(data)?;
+
+    let size = tree.size();
+    let scale = match (settings.width, settings.dpi) {
+        (Some(width), _) => width as f32 / size.width(),
+        (None, Some(dpi)) => dpi / SVG_BASE_DPI,
+        (None, None) => 1.0,
+    };
+    let width = (size.width() * scale).round().max(1.0) as u32;
+    let height = (size.height() * scale).round().max(1.0) as u32;
+    limits.check_dimensions(width, height)?;
+
+    debug!(
+        "Rendering {}x{} SVG at {}x{}",
+        size.width(),
+        size.height(),
+        width,
+        height
+    );
+
+    let mut pixmap = Pixmap::new(width, height)
+        .ok_or_else(|| anyhow::anyhow!("Invalid SVG render size {}x{}", width, height))?;
+    resvg::render(
+        &tree,
+        Transform::from_scale(width as f32 / size.width(), height as f32 / size.height()),
+        &mut pixmap.as_mut(),
+    );
+
+    // tiny-skia works in premultiplied alpha
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+    let img = RgbaImage::from_raw(width, height, pixels)
+        .ok_or_else(|| anyhow::anyhow!("SVG render produced a short buffer"))?;
+    Ok(DynamicImage::ImageRgba8(img))
+}
+
+/// Width and height of an SVG document in CSS pixels
+pub fn intrinsic_size(data: &[u8]) -> Result<(f32, f32)> {
+    let size = parse(data)?.size();
+    Ok((size.width(), size.height()))
+}
+
+fn parse(data: &[u8]) -> Result<usvg::Tree> {
+    let options = usvg::Options {
+        // Uploads must not read files off the server, so only images embedded
+        // as data URLs are resolved
+        image_href_resolver: ImageHrefResolver {
+            resolve_data: ImageHrefResolver::default_data_resolver(),
+            resolve_string: Box::new(|_, _| None),
+        },
+        font_family: "DejaVu Sans".to_string(),
+        fontdb: font_database(),
+        ..Default::default()
+    };
+    usvg::Tree::from_data(data, &options).with_context(|| "Failed to parse SVG")
+}
+
+/// Fonts for SVG text: only the embedded caption font, so renders don't
+/// depend on what the host has installed
+fn font_database() -> Arc<fontdb::Database> {
+    static FONTS: OnceLock<Arc<fontdb::Database>> = OnceLock::new();
+    FONTS
+        .get_or_init(|| {
+            let mut fonts = fontdb::Database::new();
+            fonts.load_font_data(FONT_DATA.to_vec());
+            fonts.set_sans_serif_family("DejaVu Sans");
+            fonts.set_serif_family("DejaVu Sans");
+            fonts.set_monospace_family("DejaVu Sans");
+            Arc::new(fonts)
+        })
+        .clone()
+}
@@ -1,4 +1,5 @@
 use anyhow::{Context, Result};
+use image::DynamicImage;
 use std::ffi::OsStr;
 use std::fmt;
 use std::path::Path;
@@ -43,6 +44,20 @@ pub fn is_image_file<P: AsRef<Path>>(path: P) -> bool {
     }
 }
 
+/// Whether an image has more than 8 bits per channel, so processing it
+/// should keep 16 bits
+pub fn is_high_depth(img: &DynamicImage) -> bool {
+    matches!(
+        img,
+        DynamicImage::ImageLuma16(_)
+            | DynamicImage::ImageLumaA16(_)
+            | DynamicImage::ImageRgb16(_)
+            | DynamicImage::ImageRgba16(_)
+            | DynamicImage::ImageRgb32F(_)
+            | DynamicImage::ImageRgba32F(_)
+    )
+}
+
 /// Create a directory if it doesn't exist
 pub async fn ensure_dir_exists<P: AsRef<Path>>(path: P) -> Result<()> {
     let path = path.as_ref();
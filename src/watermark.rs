@@ -0,0 +1,147 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, RgbaImage};
+use std::sync::Arc;
+use tracing::debug;
+
+// Defaults for the placement of the logo
+const WATERMARK_MARGIN: u32 = 16;
+const WATERMARK_OPACITY: f32 = 0.5;
+const WATERMARK_SCALE: f32 = 0.2;
+
+/// Where the watermark goes on the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+    /// Repeated across the whole image, `margin` apart
+    Tiled,
+}
+
+impl WatermarkPosition {
+    /// Parse a position as sent by the client
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().replace('_', "-").as_str() {
+            "top-left" => Some(WatermarkPosition::TopLeft),
+            "top-right" => Some(WatermarkPosition::TopRight),
+            "bottom-left" => Some(WatermarkPosition::BottomLeft),
+            "bottom-right" => Some(WatermarkPosition::BottomRight),
+            "center" | "centre" => Some(WatermarkPosition::Center),
+            "tiled" | "tile" => Some(WatermarkPosition::Tiled),
+            _ => None,
+        }
+    }
+}
+
+/// A logo stamped onto images before encoding
+#[derive(Debug, Clone)]
+pub struct WatermarkSettings {
+    /// Name of the uploaded watermark asset
+    pub name: Option<String>,
+    /// The asset itself, loaded by name before optimizing
+    pub image: Option<Arc<RgbaImage>>,
+    pub position: WatermarkPosition,
+    /// Distance from the edges, and between tiles, in pixels
+    pub margin: u32,
+    /// Multiplies the logo's own alpha (0-1)
+    pub opacity: f32,
+    /// Logo width as a fraction of the image width
+    pub scale: f32,
+}
+
+impl Default for WatermarkSettings {
+    fn default() -> Self {
+        Self {
+            name: None,
+            image: None,
+            position: WatermarkPosition::BottomRight,
+            margin: WATERMARK_MARGIN,
+            opacity: WATERMARK_OPACITY,
+            scale: WATERMARK_SCALE,
+        }
+    }
+}
+
+impl WatermarkSettings {
+    /// Whether a watermark will be stamped
+    pub fn is_active(&self) -> bool {
+        self.image.is_some() && self.opacity > 0.0
+    }
+}
+
+/// Stamp the watermark onto an image. Images without an active watermark are
+/// returned as they are.
+pub fn apply(img: DynamicImage, settings: &WatermarkSettings) -> DynamicImage {
+    let Some(logo) = settings.image.as_deref().filter(|_| settings.is_active()) else {
+        return img;
+    };
+
+    let mut canvas = img.into_rgba8();
+    let (width, height) = canvas.dimensions();
+
+    // Scale the logo to the image, but never past its edges
+    let logo_width = ((width as f32 * settings.scale).round() as u32).clamp(1, width);
+    let logo_height = ((logo.height() as f32 * logo_width as f32 / logo.width() as f32).round()
+        as u32)
+        .clamp(1, height);
+    let mut logo = image::imageops::resize(logo, logo_width, logo_height, FilterType::Triangle);
+
+    let opacity = settings.opacity.min(1.0);
+    for pixel in logo.pixels_mut() {
+        pixel.0[3] = (pixel.0[3] as f32 * opacity).round() as u8;
+    }
+
+    let positions = placements(
+        settings.position,
+        settings.margin,
+        (width, height),
+        (logo_width, logo_height),
+    );
+
+    debug!(
+        "Stamping {}x{} watermark at {} position(s)",
+        logo_width,
+        logo_height,
+        positions.len()
+    );
+
+    for (x, y) in positions {
+        image::imageops::overlay(&mut canvas, &logo, x as i64, y as i64);
+    }
+
+    DynamicImage::ImageRgba8(canvas)
+}
+
+/// Top-left corners for an overlay of `size` on a canvas of `canvas` pixels,
+/// `margin` away from the edges. Tiling starts at the margin and repeats the
+/// overlay `margin` apart until the canvas is covered.
+pub fn placements(
+    position: WatermarkPosition,
+    margin: u32,
+    canvas: (u32, u32),
+    size: (u32, u32),
+) -> Vec<(u32, u32)> {
+    let (width, height) = canvas;
+    let right = width.saturating_sub(size.0.saturating_add(margin));
+    let bottom = height.saturating_sub(size.1.saturating_add(margin));
+    match position {
+        WatermarkPosition::TopLeft => vec![(margin, margin)],
+        WatermarkPosition::TopRight => vec![(right, margin)],
+        WatermarkPosition::BottomLeft => vec![(margin, bottom)],
+        WatermarkPosition::BottomRight => vec![(right, bottom)],
+        WatermarkPosition::Center => vec![(
+            width.saturating_sub(size.0) / 2,
+            height.saturating_sub(size.1) / 2,
+        )],
+        WatermarkPosition::Tiled => {
+            let step_x = size.0.saturating_add(margin).max(1) as usize;
+            let step_y = size.1.saturating_add(margin).max(1) as usize;
+            (margin..height)
+                .step_by(step_y)
+                .flat_map(|y| (margin..width).step_by(step_x).map(move |x| (x, y)))
+                .collect()
+        }
+    }
+}
@@ -443,6 +443,13 @@ function displayResults(results) {
         100
       ).toFixed(1);
       compressionRatio.textContent = `${savedPercentage}%`;
+
+      // Objective quality, only reported when a perceptual target was requested
+      if (typeof result.ssim === "number") {
+        resultTemplate.querySelector(".ssim-row").style.display = "";
+        resultTemplate.querySelector(".ssim-score").textContent =
+          result.ssim.toFixed(4);
+      }
     } else {
       // For rename mode, hide the compression metrics
       const originalSizeRow =